@@ -13,16 +13,132 @@ CREATE TABLE IF NOT EXISTS `Issues` (
     `created_at` text,
     `title` text NOT NULL,
     `comments_url` text NOT NULL,
+    `lock_reason` text,
+    `state` text,
+    `is_pull_request` INTEGER(1) NOT NULL DEFAULT 0,
+    `number` INTEGER,
+    `body` text,
+    `user_login` text,
+    `user_id` INTEGER,
+    `labels` text,
     PRIMARY KEY (`id_issue`),
     FOREIGN KEY(`id_repo`) REFERENCES Repositories(`id_repo`)
 );
 
 CREATE TABLE IF NOT EXISTS `Comments` (
     `id_comment` INTEGER,
+    `kind` text NOT NULL DEFAULT 'issue_comment',
     `id_issue` INTEGER,
     `created_at` text,
     `text` TEXT NOT NULL,
     `is_toxic` INTEGER(1) NOT NULL DEFAULT 0,
-    PRIMARY KEY (`id_comment`),
+    `user_login` text,
+    `user_id` INTEGER,
+    `author_association` text,
+    `updated_at` text,
+    `node_id` text,
+    `is_minimized` INTEGER(1),
+    `minimized_reason` text,
+    PRIMARY KEY (`id_comment`, `kind`),
     FOREIGN KEY(`id_issue`) REFERENCES Issues(`id_issue`)
 );
+
+CREATE TABLE IF NOT EXISTS `FailedRequests` (
+    `url` text NOT NULL,
+    `status` INTEGER,
+    `error` text NOT NULL,
+    `failed_at` text NOT NULL,
+    PRIMARY KEY (`url`)
+);
+
+CREATE TABLE IF NOT EXISTS `TruncatedRequests` (
+    `url` text NOT NULL,
+    `pages` INTEGER NOT NULL,
+    `truncated_at` text NOT NULL,
+    PRIMARY KEY (`url`)
+);
+
+CREATE TABLE IF NOT EXISTS `SampledRanges` (
+    `since` INTEGER,
+    `until` INTEGER NOT NULL,
+    `sampled_at` text NOT NULL,
+    PRIMARY KEY (`since`)
+);
+
+CREATE TABLE IF NOT EXISTS `RepositoryStrata` (
+    `id_repo` INTEGER,
+    `language` text,
+    `stars_bucket` INTEGER,
+    `created_year` INTEGER,
+    `sampled_at` text NOT NULL,
+    PRIMARY KEY (`id_repo`)
+);
+
+CREATE TABLE IF NOT EXISTS `LockEvents` (
+    `id_event` INTEGER,
+    `id_issue` INTEGER NOT NULL,
+    `event` text NOT NULL,
+    `actor` text,
+    `created_at` text,
+    `lock_reason` text,
+    PRIMARY KEY (`id_event`),
+    FOREIGN KEY(`id_issue`) REFERENCES Issues(`id_issue`)
+);
+
+CREATE TABLE IF NOT EXISTS `TimelineEvents` (
+    `id_issue` INTEGER NOT NULL,
+    `position` INTEGER NOT NULL,
+    `id_event` INTEGER,
+    `event` text NOT NULL,
+    `actor` text,
+    `created_at` text,
+    `detail` text,
+    PRIMARY KEY (`id_issue`, `position`),
+    FOREIGN KEY(`id_issue`) REFERENCES Issues(`id_issue`)
+);
+
+CREATE TABLE IF NOT EXISTS `CommentReactions` (
+    `id_comment` INTEGER NOT NULL,
+    `kind` text NOT NULL,
+    `total_count` INTEGER NOT NULL,
+    `plus_one` INTEGER NOT NULL,
+    `minus_one` INTEGER NOT NULL,
+    `laugh` INTEGER NOT NULL,
+    `hooray` INTEGER NOT NULL,
+    `confused` INTEGER NOT NULL,
+    `heart` INTEGER NOT NULL,
+    `rocket` INTEGER NOT NULL,
+    `eyes` INTEGER NOT NULL,
+    PRIMARY KEY (`id_comment`, `kind`),
+    FOREIGN KEY(`id_comment`, `kind`) REFERENCES Comments(`id_comment`, `kind`)
+);
+
+CREATE TABLE IF NOT EXISTS `CommentRevisions` (
+    `id_revision` text NOT NULL,
+    `id_comment` INTEGER NOT NULL,
+    `kind` text NOT NULL,
+    `edited_at` text NOT NULL,
+    `deleted_at` text,
+    `editor` text,
+    `text` text,
+    PRIMARY KEY (`id_revision`),
+    FOREIGN KEY(`id_comment`, `kind`) REFERENCES Comments(`id_comment`, `kind`)
+);
+
+CREATE TABLE IF NOT EXISTS `RepositorySnapshots` (
+    `id_repo` INTEGER NOT NULL,
+    `fetched_at` text NOT NULL,
+    `stargazers_count` INTEGER NOT NULL,
+    `forks_count` INTEGER NOT NULL,
+    `subscribers_count` INTEGER,
+    `open_issues_count` INTEGER NOT NULL,
+    `language` text,
+    `topics` text NOT NULL,
+    `license` text,
+    `archived` INTEGER(1) NOT NULL,
+    `owner_type` text NOT NULL,
+    `created_at` text NOT NULL,
+    `pushed_at` text,
+    PRIMARY KEY (`id_repo`, `fetched_at`),
+    FOREIGN KEY(`id_repo`) REFERENCES Repositories(`id_repo`)
+);
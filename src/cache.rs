@@ -0,0 +1,62 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// A response as it's kept on disk, with the validators needed to ask GitHub
+/// whether it changed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CachedResponse {
+    pub url: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub next: Option<String>,
+    #[serde(default)]
+    pub last: Option<String>,
+    pub body: String,
+}
+
+/// Directory of previously fetched responses, one JSON file per URL.
+pub struct Cache {
+    dir: PathBuf,
+    offline: bool,
+}
+
+impl Cache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir).expect("failed to create cache directory");
+        Cache {
+            dir,
+            offline: false,
+        }
+    }
+
+    /// Serve every request from the cache and never touch the network.
+    pub fn offline(mut self, offline: bool) -> Self {
+        self.offline = offline;
+        self
+    }
+
+    pub fn is_offline(&self) -> bool {
+        self.offline
+    }
+
+    /// The response stored under `key`, the URL for plain GET requests.
+    pub async fn get(&self, key: &str) -> Option<CachedResponse> {
+        let contents = tokio::fs::read(self.path(key)).await.ok()?;
+        serde_json::from_slice(&contents).ok()
+    }
+
+    pub async fn put(&self, key: &str, response: &CachedResponse) {
+        let contents = serde_json::to_vec(response).expect("failed to serialize response");
+        if let Err(err) = tokio::fs::write(self.path(key), contents).await {
+            println!("Failed to cache {}: {}", response.url, err);
+        }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        let key = hex::encode(Sha256::digest(key.as_bytes()));
+        self.dir.join(format!("{}.json", key))
+    }
+}
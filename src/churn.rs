@@ -0,0 +1,273 @@
+use std::collections::BTreeSet;
+
+use futures_util::stream::{self, StreamExt};
+use serde::Serialize;
+use sqlx::{sqlite::SqliteConnection, Row};
+
+use crate::github::Github;
+use crate::{before_or_after, fetch_commits, get_since_and_until, Comment, Commit};
+
+/// An issue and the moment its contributors are compared around.
+struct HeatedIssue {
+    id_issue: i64,
+    repository_url: String,
+    pivot: String,
+    pivot_source: &'static str,
+}
+
+#[derive(Serialize, Debug)]
+struct ChurnRow {
+    id_issue: i64,
+    pivot: String,
+    /// `locked`, `toxic_comment` or `created_at`
+    pivot_source: String,
+    contributors_before: usize,
+    contributors_after: usize,
+    retained: usize,
+    departed: usize,
+    arrived: usize,
+}
+
+/// Who of the contributors before the pivot stayed or left, and who came.
+#[derive(Debug, PartialEq)]
+struct Churn<'a> {
+    retained: Vec<&'a String>,
+    departed: Vec<&'a String>,
+    arrived: Vec<&'a String>,
+}
+
+impl<'a> Churn<'a> {
+    fn new(before: &'a BTreeSet<String>, after: &'a BTreeSet<String>) -> Self {
+        Churn {
+            retained: before.intersection(after).collect(),
+            departed: before.difference(after).collect(),
+            arrived: after.difference(before).collect(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct ContributorRow {
+    id_issue: i64,
+    contributor: String,
+    /// `retained`, `departed` or `arrived`
+    status: String,
+}
+
+/// For every issue locked as too heated, the distinct commit authors and
+/// issue commenters of its repository in the 30 days before and after it was
+/// last locked as too heated, or failing that its first toxic comment or its
+/// creation. Counts go in churn.csv and who was retained, departed or arrived
+/// in churn_contributors.csv.
+pub async fn contributor_churn(conn: &mut SqliteConnection, client: &Github) {
+    let mut summary = csv::Writer::from_path("churn.csv").unwrap();
+    let mut details = csv::Writer::from_path("churn_contributors.csv").unwrap();
+
+    let mut results = stream::iter(heated_issues(conn).await)
+        .map(|issue| async move {
+            let (before, after) = contributors(client, &issue).await;
+            (issue, before, after)
+        })
+        .buffer_unordered(client.max_parallelism());
+
+    while let Some((issue, before, after)) = results.next().await {
+        let Churn {
+            retained,
+            departed,
+            arrived,
+        } = Churn::new(&before, &after);
+
+        summary
+            .serialize(ChurnRow {
+                id_issue: issue.id_issue,
+                pivot: issue.pivot.clone(),
+                pivot_source: issue.pivot_source.to_string(),
+                contributors_before: before.len(),
+                contributors_after: after.len(),
+                retained: retained.len(),
+                departed: departed.len(),
+                arrived: arrived.len(),
+            })
+            .expect("failed to write churn to csv");
+
+        for (status, contributors) in [
+            ("retained", retained),
+            ("departed", departed),
+            ("arrived", arrived),
+        ] {
+            for contributor in contributors {
+                details
+                    .serialize(ContributorRow {
+                        id_issue: issue.id_issue,
+                        contributor: contributor.clone(),
+                        status: status.to_string(),
+                    })
+                    .expect("failed to write contributor to csv");
+            }
+        }
+
+        summary.flush().unwrap();
+        details.flush().unwrap();
+    }
+}
+
+/// Issues locked as too heated, with the moment to compare contributors
+/// around. An issue may have been locked for other reasons before, so the
+/// last lock as too heated is used.
+async fn heated_issues(conn: &mut SqliteConnection) -> Vec<HeatedIssue> {
+    sqlx::query(
+        r#"
+        SELECT Issues.id_issue as id_issue, Issues.created_at as created_at, Repositories.commits_url as commits_url,
+               (SELECT MAX(created_at) FROM LockEvents
+                 WHERE LockEvents.id_issue = Issues.id_issue AND event = 'locked'
+                   AND lock_reason = 'too heated') as locked_at,
+               (SELECT MIN(created_at) FROM Comments
+                 WHERE Comments.id_issue = Issues.id_issue AND is_toxic = 1) as toxic_at
+          FROM Issues, Repositories
+         WHERE Issues.id_repo = Repositories.id_repo AND Issues.lock_reason = 'too heated'
+        "#,
+    )
+    .fetch_all(&mut *conn)
+    .await
+    .unwrap()
+    .iter()
+    .filter_map(|issue| {
+        let commits_url: String = issue.get("commits_url");
+        let repository_url = commits_url.strip_suffix("/commits{/sha}")?.to_string();
+
+        let (pivot, pivot_source) = match (
+            issue.get::<Option<String>, _>("locked_at"),
+            issue.get::<Option<String>, _>("toxic_at"),
+            issue.get::<Option<String>, _>("created_at"),
+        ) {
+            (Some(locked_at), _, _) => (locked_at, "locked"),
+            (None, Some(toxic_at), _) => (toxic_at, "toxic_comment"),
+            (None, None, Some(created_at)) => (created_at, "created_at"),
+            (None, None, None) => return None,
+        };
+
+        Some(HeatedIssue {
+            id_issue: issue.get("id_issue"),
+            repository_url,
+            pivot,
+            pivot_source,
+        })
+    })
+    .collect()
+}
+
+/// Everyone who committed to or commented on an issue of the repository in
+/// the window before and after the pivot. People are told apart by login,
+/// or by email for commits not linked to an account.
+async fn contributors(
+    client: &Github,
+    issue: &HeatedIssue,
+) -> (BTreeSet<String>, BTreeSet<String>) {
+    let (since, until) = get_since_and_until(&issue.pivot);
+    let commits_url = format!("{}/commits", issue.repository_url);
+
+    let commit_author = |commit: Commit| match commit.author {
+        Some(author) => author.login,
+        None => commit.commit.author.email,
+    };
+
+    let mut before: BTreeSet<String> = fetch_commits(client, &commits_url, &since, &issue.pivot)
+        .await
+        .into_iter()
+        .map(commit_author)
+        .collect();
+    let mut after: BTreeSet<String> = fetch_commits(client, &commits_url, &issue.pivot, &until)
+        .await
+        .into_iter()
+        .map(commit_author)
+        .collect();
+
+    // `since` filters on when comments were last updated, so older ones
+    // still show up and are skipped by their creation date.
+    let comments_url = format!(
+        "{}/issues/comments?per_page=100&sort=created&direction=asc&since={}",
+        issue.repository_url, since
+    );
+    println!("Retrieving Comments: {}", comments_url);
+
+    let mut comments = client
+        .paginate::<Vec<Comment>>(&comments_url)
+        .flat_map(stream::iter)
+        .take_while(|comment| std::future::ready(comment.created_at <= until));
+
+    while let Some(comment) = comments.next().await {
+        let Some(user) = comment.user else {
+            continue;
+        };
+        match before_or_after(&comment.created_at, &since, &issue.pivot, &until) {
+            Some("before") => before.insert(user.login),
+            Some(_) => after.insert(user.login),
+            None => false,
+        };
+    }
+
+    (before, after)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::{Connection, Executor};
+
+    fn contributors(names: &[&str]) -> BTreeSet<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn churn_splits_contributors_around_the_pivot() {
+        let before = contributors(&["alice", "bob", "carol"]);
+        let after = contributors(&["bob", "dave", "alice"]);
+
+        let churn = Churn::new(&before, &after);
+
+        assert_eq!(churn.retained, ["alice", "bob"]);
+        assert_eq!(churn.departed, ["carol"]);
+        assert_eq!(churn.arrived, ["dave"]);
+    }
+
+    #[test]
+    fn churn_without_contributors_after_departs_everyone() {
+        let before = contributors(&["alice", "a@example.com"]);
+        let after = BTreeSet::new();
+
+        let churn = Churn::new(&before, &after);
+
+        assert!(churn.retained.is_empty());
+        assert_eq!(churn.departed, ["a@example.com", "alice"]);
+        assert!(churn.arrived.is_empty());
+    }
+
+    #[tokio::test]
+    async fn heated_issues_pivot_on_the_last_too_heated_lock() {
+        let mut conn = SqliteConnection::connect("sqlite::memory:").await.unwrap();
+        crate::schema::migrate(&mut conn).await;
+        conn.execute(
+            "INSERT INTO Repositories (id_repo, name, stars_url, forks_url, commits_url) \
+             VALUES (5, 'o/r', 's', 'f', 'https://api.github.com/repos/o/r/commits{/sha}'); \
+             INSERT INTO Issues (id_issue, id_repo, created_at, title, comments_url, lock_reason) \
+             VALUES (1, 5, '2023-02-01T00:00:00Z', 't', 'u', 'too heated'); \
+             INSERT INTO LockEvents (id_event, id_issue, event, created_at, lock_reason) VALUES \
+             (101, 1, 'locked', '2023-03-01T00:00:00Z', 'too heated'), \
+             (102, 1, 'unlocked', '2023-03-02T00:00:00Z', NULL), \
+             (103, 1, 'locked', '2023-03-03T00:00:00Z', 'off-topic'), \
+             (104, 1, 'unlocked', '2023-03-04T00:00:00Z', NULL), \
+             (105, 1, 'locked', '2023-03-05T00:00:00Z', 'too heated'), \
+             (106, 1, 'unlocked', '2023-03-06T00:00:00Z', NULL), \
+             (107, 1, 'locked', '2023-03-07T00:00:00Z', 'spam')",
+        )
+        .await
+        .unwrap();
+
+        let issues = heated_issues(&mut conn).await;
+
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].repository_url, "https://api.github.com/repos/o/r");
+        assert_eq!(issues[0].pivot, "2023-03-05T00:00:00Z");
+        assert_eq!(issues[0].pivot_source, "locked");
+    }
+}
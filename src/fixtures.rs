@@ -0,0 +1,116 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue},
+    Response, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::github::Request;
+
+/// A recorded response, stored as JSON so fixtures can be inspected and
+/// edited by hand.
+#[derive(Serialize, Deserialize, Debug)]
+struct Fixture {
+    url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    request_body: Option<String>,
+    status: u16,
+    headers: BTreeMap<String, String>,
+    body: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Send requests as usual and write every response to the fixtures
+    /// directory.
+    Record,
+    /// Answer every request from the fixtures directory.
+    Replay,
+}
+
+/// Directory of recorded GitHub traffic, one file per URL.
+pub struct Fixtures {
+    dir: PathBuf,
+    mode: Mode,
+}
+
+impl Fixtures {
+    pub fn new(dir: impl Into<PathBuf>, mode: Mode) -> Self {
+        let dir = dir.into();
+        if mode == Mode::Record {
+            std::fs::create_dir_all(&dir).expect("failed to create fixtures directory");
+        }
+        Fixtures { dir, mode }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Write `response` to the fixtures directory and hand back an identical
+    /// response for the caller to consume.
+    pub async fn record(
+        &self,
+        request: Request<'_>,
+        response: Response,
+    ) -> Result<Response, reqwest::Error> {
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = response.text().await?;
+
+        let fixture = Fixture {
+            url: request.url.to_string(),
+            request_body: request.body.map(String::from),
+            status: status.as_u16(),
+            headers: headers
+                .iter()
+                .filter_map(|(name, value)| {
+                    Some((name.to_string(), value.to_str().ok()?.to_string()))
+                })
+                .collect(),
+            body,
+        };
+
+        let contents = serde_json::to_vec_pretty(&fixture).expect("failed to serialize fixture");
+        tokio::fs::write(self.path(request), contents)
+            .await
+            .expect("failed to write fixture");
+
+        Ok(build_response(status, headers, fixture.body))
+    }
+
+    /// The recorded response to `request`, if there is one.
+    pub async fn replay(&self, request: Request<'_>) -> Option<Response> {
+        let contents = tokio::fs::read(self.path(request)).await.ok()?;
+        let fixture: Fixture = serde_json::from_slice(&contents).ok()?;
+
+        let status = StatusCode::from_u16(fixture.status).ok()?;
+        let headers = fixture
+            .headers
+            .iter()
+            .filter_map(|(name, value)| {
+                Some((
+                    HeaderName::from_bytes(name.as_bytes()).ok()?,
+                    HeaderValue::from_str(value).ok()?,
+                ))
+            })
+            .collect();
+
+        Some(build_response(status, headers, fixture.body))
+    }
+
+    fn path(&self, request: Request<'_>) -> PathBuf {
+        let key = hex::encode(Sha256::digest(request.key().as_bytes()));
+        self.dir.join(format!("{}.json", key))
+    }
+}
+
+fn build_response(status: StatusCode, headers: HeaderMap, body: String) -> Response {
+    let mut response = http::Response::new(body);
+    *response.status_mut() = status;
+    *response.headers_mut() = headers;
+    Response::from(response)
+}
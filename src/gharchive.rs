@@ -0,0 +1,261 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+use flate2::read::MultiGzDecoder;
+use serde::Deserialize;
+use serde_json::Value;
+use sqlx::sqlite::SqliteConnection;
+
+use crate::{
+    store_comments, store_issues, store_respository, Comment, Issue, IssueFilter, Repository,
+};
+
+#[derive(Deserialize, Debug)]
+struct Event {
+    #[serde(rename = "type")]
+    kind: String,
+    repo: EventRepository,
+    payload: Value,
+}
+
+#[derive(Deserialize, Debug)]
+struct EventRepository {
+    id: i64,
+    /// `owner/name`
+    name: String,
+    url: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct IssuePayload {
+    issue: Issue,
+    comment: Option<Comment>,
+}
+
+/// Build the dataset from GH Archive hourly dumps (`.json.gz`) instead of the
+/// API. `paths` are dump files or directories containing them.
+///
+/// The first pass finds every issue that was seen locked, and matching
+/// `filter`, in an `IssuesEvent` or `IssueCommentEvent`, the second collects
+/// the comments posted on those issues.
+pub async fn ingest(conn: &mut SqliteConnection, paths: &[String], filter: &IssueFilter) {
+    let files = dump_files(paths);
+    println!("Reading {} GH Archive files", files.len());
+
+    let mut issues: HashMap<i64, (Issue, Repository)> = HashMap::new();
+    for file in &files {
+        for_each_issue_event(file, |repository, IssuePayload { mut issue, .. }| {
+            if filter.matches(&issue) {
+                issue.repository_id = Some(repository.id);
+                issues
+                    .entry(issue.id)
+                    .or_insert_with(|| (issue, repository.into()));
+            }
+        });
+    }
+    println!("Found {} locked issues", issues.len());
+
+    let mut comments = HashSet::new();
+    for file in &files {
+        for_each_issue_event(file, |_, IssuePayload { issue, comment }| {
+            if let Some(mut comment) = comment.filter(|_| issues.contains_key(&issue.id)) {
+                comment.issue_id = Some(issue.id);
+                comments.insert(comment);
+            }
+        });
+    }
+    println!("Found {} comments on locked issues", comments.len());
+
+    let mut repositories = HashMap::new();
+    let mut locked_issues = HashSet::new();
+    for (issue, repository) in issues.into_values() {
+        repositories.entry(repository.id).or_insert(repository);
+        locked_issues.insert(issue);
+    }
+
+    for repository in repositories.into_values() {
+        store_respository(conn, repository).await;
+    }
+    store_issues(conn, locked_issues).await;
+    store_comments(conn, comments, "issue_comment").await;
+}
+
+/// Every `.json.gz` file in `paths`, in name order, which for GH Archive is
+/// chronological.
+fn dump_files(paths: &[String]) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    for path in paths.iter().map(PathBuf::from) {
+        if path.is_dir() {
+            let entries = std::fs::read_dir(&path).expect("failed to read GH Archive directory");
+            files.extend(
+                entries
+                    .filter_map(|entry| Some(entry.ok()?.path()))
+                    .filter(|path| path.to_string_lossy().ends_with(".json.gz")),
+            );
+        } else {
+            files.push(path);
+        }
+    }
+    files.sort();
+    files
+}
+
+/// Call `f` with the repository and payload of every issue and issue comment
+/// event in a dump. Lines that don't parse, e.g. events in the older formats,
+/// are skipped.
+fn for_each_issue_event(file: &Path, mut f: impl FnMut(EventRepository, IssuePayload)) {
+    let reader = match File::open(file) {
+        Ok(reader) => BufReader::new(MultiGzDecoder::new(reader)),
+        Err(err) => {
+            println!("Failed to open {}: {}", file.display(), err);
+            return;
+        }
+    };
+
+    for line in reader.lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(err) => {
+                println!("Failed to read {}: {}", file.display(), err);
+                return;
+            }
+        };
+
+        let Ok(event) = serde_json::from_str::<Event>(&line) else {
+            continue;
+        };
+        if event.kind != "IssuesEvent" && event.kind != "IssueCommentEvent" {
+            continue;
+        }
+        if let Ok(payload) = serde_json::from_value(event.payload) {
+            f(event.repo, payload);
+        }
+    }
+}
+
+impl From<EventRepository> for Repository {
+    fn from(repository: EventRepository) -> Self {
+        let name = match repository.name.split_once('/') {
+            Some((_, name)) => name.to_string(),
+            None => repository.name.clone(),
+        };
+
+        Repository {
+            id: repository.id,
+            name,
+            forks_url: format!("{}/forks", repository.url),
+            stargazers_url: format!("{}/stargazers", repository.url),
+            commits_url: format!("{}/commits{{/sha}}", repository.url),
+            issues_url: format!("{}/issues{{/number}}", repository.url),
+            full_name: repository.name,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::{Connection, Row};
+
+    use super::*;
+    use crate::schema;
+
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/gharchive");
+
+    #[test]
+    fn repository_urls_are_derived_from_the_event() {
+        let repository: Repository = EventRepository {
+            id: 5,
+            name: "o/r".to_string(),
+            url: "https://api.github.com/repos/o/r".to_string(),
+        }
+        .into();
+
+        assert_eq!(repository.name, "r");
+        assert_eq!(repository.full_name, "o/r");
+        assert_eq!(
+            repository.commits_url,
+            "https://api.github.com/repos/o/r/commits{/sha}"
+        );
+        assert_eq!(
+            repository.issues_url,
+            "https://api.github.com/repos/o/r/issues{/number}"
+        );
+    }
+
+    #[test]
+    fn only_issue_events_are_read() {
+        let mut kinds = Vec::new();
+        for_each_issue_event(
+            &PathBuf::from(FIXTURES).join("2023-03-01-10.json.gz"),
+            |repository, payload| {
+                kinds.push((repository.name, payload.comment.is_some()));
+            },
+        );
+
+        assert_eq!(
+            kinds,
+            [("o/r".to_string(), false), ("o/r".to_string(), true)]
+        );
+    }
+
+    #[tokio::test]
+    async fn ingest_stores_locked_issues_and_their_comments() {
+        let mut conn = SqliteConnection::connect("sqlite::memory:").await.unwrap();
+        schema::migrate(&mut conn).await;
+        let filter = IssueFilter {
+            lock_reasons: vec!["too heated".to_string()],
+            states: vec!["closed".to_string()],
+        };
+
+        ingest(&mut conn, &[FIXTURES.to_string()], &filter).await;
+
+        let repository = sqlx::query("SELECT id_repo, name, commits_url FROM Repositories")
+            .fetch_one(&mut conn)
+            .await
+            .unwrap();
+        assert_eq!(repository.get::<i64, _>("id_repo"), 5);
+        assert_eq!(repository.get::<String, _>("name"), "r");
+
+        let issue =
+            sqlx::query("SELECT id_issue, id_repo, number, lock_reason, user_login FROM Issues")
+                .fetch_one(&mut conn)
+                .await
+                .unwrap();
+        assert_eq!(issue.get::<i64, _>("id_issue"), 1001);
+        assert_eq!(issue.get::<i64, _>("id_repo"), 5);
+        assert_eq!(issue.get::<i64, _>("number"), 7);
+        assert_eq!(issue.get::<String, _>("lock_reason"), "too heated");
+        assert_eq!(issue.get::<String, _>("user_login"), "alice");
+
+        let comment =
+            sqlx::query("SELECT id_comment, kind, id_issue, text, user_login FROM Comments")
+                .fetch_one(&mut conn)
+                .await
+                .unwrap();
+        assert_eq!(comment.get::<i64, _>("id_comment"), 2002);
+        assert_eq!(comment.get::<String, _>("kind"), "issue_comment");
+        assert_eq!(comment.get::<i64, _>("id_issue"), 1001);
+        assert_eq!(comment.get::<String, _>("text"), "This is still broken.");
+        assert_eq!(comment.get::<String, _>("user_login"), "bob");
+    }
+
+    #[tokio::test]
+    async fn ingest_skips_issues_locked_for_other_reasons() {
+        let mut conn = SqliteConnection::connect("sqlite::memory:").await.unwrap();
+        schema::migrate(&mut conn).await;
+        let filter = IssueFilter {
+            lock_reasons: vec!["spam".to_string()],
+            states: vec!["closed".to_string()],
+        };
+
+        ingest(&mut conn, &[FIXTURES.to_string()], &filter).await;
+
+        let issues: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM Issues")
+            .fetch_one(&mut conn)
+            .await
+            .unwrap();
+        assert_eq!(issues, 0);
+    }
+}
@@ -0,0 +1,1034 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures_util::future;
+use futures_util::stream::{self, BoxStream, StreamExt};
+use reqwest::{
+    self,
+    header::{
+        HeaderMap, HeaderValue, ACCEPT, AUTHORIZATION, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH,
+        LAST_MODIFIED, LINK, RETRY_AFTER, USER_AGENT,
+    },
+    Client, Response, StatusCode, Url,
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use tokio::sync::Semaphore;
+
+use crate::cache::{Cache, CachedResponse};
+use crate::fixtures::{Fixtures, Mode};
+use crate::tokens::{Token, TokenPool};
+
+pub const DEFAULT_API_URL: &str = "https://api.github.com";
+pub const USER_AGENT_NAME: &str = "toxicity-metodologia";
+
+/// Delay before the first retry, doubled on every following attempt.
+const BASE_RETRY_DELAY: Duration = Duration::from_secs(2);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(120);
+
+/// How long to back off after a secondary rate limit that didn't tell us how
+/// long to wait. GitHub asks for at least a minute.
+const SECONDARY_LIMIT_WAIT: Duration = Duration::from_secs(60);
+
+/// How many times a request is sent again after being rate limited before
+/// giving up on it.
+const MAX_RATE_LIMIT_RETRIES: u32 = 10;
+
+pub trait GetGithub {
+    fn get_github(&self, url: &str, authorization: Option<&str>) -> reqwest::RequestBuilder;
+    fn post_github(&self, url: &str, authorization: Option<&str>) -> reqwest::RequestBuilder;
+}
+
+impl GetGithub for reqwest::Client {
+    fn get_github(&self, url: &str, authorization: Option<&str>) -> reqwest::RequestBuilder {
+        with_github_headers(self.get(url), authorization)
+    }
+
+    fn post_github(&self, url: &str, authorization: Option<&str>) -> reqwest::RequestBuilder {
+        with_github_headers(self.post(url), authorization)
+    }
+}
+
+fn with_github_headers(
+    request: reqwest::RequestBuilder,
+    authorization: Option<&str>,
+) -> reqwest::RequestBuilder {
+    let request = request
+        .header(ACCEPT, "application/vnd.github+json")
+        .header(USER_AGENT, USER_AGENT_NAME);
+
+    match authorization {
+        Some(authorization) => request.header(AUTHORIZATION, authorization),
+        None => request,
+    }
+}
+
+#[derive(Debug)]
+pub enum FetchError {
+    Request(reqwest::Error),
+    Status(StatusCode, String),
+    Decode(serde_json::Error),
+    NotCached,
+    NotRecorded,
+    Graphql(String),
+}
+
+impl FetchError {
+    /// Whether the same request has a chance of succeeding if sent again.
+    fn is_retryable(&self) -> bool {
+        match self {
+            FetchError::Request(_) => true,
+            FetchError::Status(status, _) => {
+                status.is_server_error() || *status == StatusCode::REQUEST_TIMEOUT
+            }
+            FetchError::Decode(_)
+            | FetchError::NotCached
+            | FetchError::NotRecorded
+            | FetchError::Graphql(_) => false,
+        }
+    }
+
+    fn status(&self) -> Option<StatusCode> {
+        match self {
+            FetchError::Status(status, _) => Some(*status),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FetchError::Request(err) => write!(f, "request failed: {}", err),
+            FetchError::Status(status, body) => write!(f, "unexpected status {}: {}", status, body),
+            FetchError::Decode(err) => write!(f, "failed to decode response: {}", err),
+            FetchError::NotCached => write!(f, "response not in cache while offline"),
+            FetchError::NotRecorded => write!(f, "no recorded response to replay"),
+            FetchError::Graphql(message) => write!(f, "GraphQL query failed: {}", message),
+        }
+    }
+}
+
+/// Rate limit budget GitHub reported for one resource (`core`, `search`,
+/// `graphql`, ...).
+#[derive(Debug, Clone, Copy)]
+pub struct Budget {
+    pub limit: u32,
+    pub remaining: u32,
+    /// Unix timestamp at which `remaining` is refilled.
+    pub reset: u64,
+}
+
+impl Budget {
+    fn from_headers(headers: &HeaderMap) -> Option<Budget> {
+        Some(Budget {
+            limit: header_value(headers, "x-ratelimit-limit")?,
+            remaining: header_value(headers, "x-ratelimit-remaining")?,
+            reset: header_value(headers, "x-ratelimit-reset")?,
+        })
+    }
+
+    pub fn time_until_reset(&self) -> Duration {
+        Duration::from_secs(self.reset.saturating_sub(unix_now()) + 1)
+    }
+
+    pub fn has_reset(&self) -> bool {
+        self.reset < unix_now()
+    }
+}
+
+/// A request we gave up on, kept around so it can be stored instead of being
+/// silently dropped.
+#[derive(Debug)]
+pub struct Failure {
+    pub url: String,
+    pub status: Option<u16>,
+    pub error: String,
+}
+
+/// A paginated listing we stopped following because it hit the page cap.
+#[derive(Debug)]
+pub struct Truncation {
+    pub url: String,
+    pub pages: usize,
+}
+
+/// A request to GitHub: a GET of `url`, or a POST of `body` to it.
+#[derive(Debug, Clone, Copy)]
+pub struct Request<'a> {
+    pub url: &'a str,
+    pub body: Option<&'a str>,
+    /// Media type to ask for instead of the default JSON, for endpoints with
+    /// extra fields behind a custom one.
+    pub accept: Option<&'a str>,
+}
+
+impl<'a> Request<'a> {
+    fn get(url: &'a str) -> Self {
+        Request {
+            url,
+            body: None,
+            accept: None,
+        }
+    }
+
+    fn post(url: &'a str, body: &'a str) -> Self {
+        Request {
+            url,
+            body: Some(body),
+            accept: None,
+        }
+    }
+
+    fn accept(mut self, accept: Option<&'a str>) -> Self {
+        self.accept = accept;
+        self
+    }
+
+    /// Identifies the request in the cache and in fixtures.
+    pub fn key(&self) -> String {
+        let mut key = self.url.to_string();
+        if let Some(accept) = self.accept {
+            key.push_str(&format!("\nAccept: {}", accept));
+        }
+        if let Some(body) = self.body {
+            key.push_str(&format!("\n{}", body));
+        }
+        key
+    }
+}
+
+/// The `rateLimit` field of a GraphQL response.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct GraphqlRateLimit {
+    cost: u64,
+    remaining: u64,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct GraphqlData {
+    rate_limit: Option<GraphqlRateLimit>,
+}
+
+#[derive(Deserialize, Debug)]
+struct GraphqlError {
+    message: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct GraphqlResponse<T> {
+    data: Option<T>,
+    #[serde(default)]
+    errors: Vec<GraphqlError>,
+}
+
+/// The parts of a response we care about once it's been received.
+struct Page {
+    body: String,
+    links: Links,
+}
+
+/// Pagination targets from a `Link` header.
+#[derive(Debug, Default)]
+struct Links {
+    next: Option<String>,
+    last: Option<String>,
+}
+
+impl From<CachedResponse> for Page {
+    fn from(cached: CachedResponse) -> Self {
+        Page {
+            body: cached.body,
+            links: Links {
+                next: cached.next,
+                last: cached.last,
+            },
+        }
+    }
+}
+
+/// A GitHub client that keeps track of the rate limit headers of every
+/// response and waits exactly as long as GitHub asks before sending more
+/// requests. Transient failures are retried with jittered exponential
+/// backoff, up to `max_attempts` times. With a cache, requests are made
+/// conditional on the cached copy so unchanged responses don't count
+/// against the rate limit. With fixtures, traffic is either recorded to disk
+/// or replayed from it instead of going to GitHub. At most `parallelism`
+/// requests are in flight at once.
+pub struct Github {
+    client: Client,
+    api_url: String,
+    graphql_url: String,
+    tokens: TokenPool,
+    cache: Option<Cache>,
+    fixtures: Option<Fixtures>,
+    max_attempts: u32,
+    max_pages: Option<usize>,
+    parallelism: usize,
+    permits: Semaphore,
+    failures: Mutex<Vec<Failure>>,
+    truncations: Mutex<Vec<Truncation>>,
+    graphql_cost: AtomicU64,
+}
+
+impl Github {
+    pub fn new(client: Client, tokens: TokenPool) -> Self {
+        Github {
+            client,
+            api_url: DEFAULT_API_URL.to_string(),
+            graphql_url: graphql_url_for(DEFAULT_API_URL),
+            tokens,
+            cache: None,
+            fixtures: None,
+            max_attempts: 1,
+            max_pages: None,
+            parallelism: 1,
+            permits: Semaphore::new(1),
+            failures: Mutex::new(Vec::new()),
+            truncations: Mutex::new(Vec::new()),
+            graphql_cost: AtomicU64::new(0),
+        }
+    }
+
+    /// Base URL of the REST API, e.g. `https://github.example.com/api/v3` for
+    /// GitHub Enterprise Server. Also points the GraphQL endpoint at the same
+    /// instance, unless it's set explicitly afterwards.
+    pub fn api_url(mut self, api_url: &str) -> Self {
+        self.api_url = api_url.trim_end_matches('/').to_string();
+        self.graphql_url = graphql_url_for(&self.api_url);
+        self
+    }
+
+    pub fn graphql_url(mut self, graphql_url: Option<&str>) -> Self {
+        if let Some(graphql_url) = graphql_url {
+            self.graphql_url = graphql_url.to_string();
+        }
+        self
+    }
+
+    pub fn cache(mut self, cache: Option<Cache>) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    pub fn fixtures(mut self, fixtures: Option<Fixtures>) -> Self {
+        self.fixtures = fixtures;
+        self
+    }
+
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// Stop following `paginate` listings after this many pages.
+    pub fn max_pages(mut self, max_pages: Option<usize>) -> Self {
+        self.max_pages = max_pages;
+        self
+    }
+
+    /// How many requests may be in flight at once.
+    pub fn parallelism(mut self, parallelism: usize) -> Self {
+        self.parallelism = parallelism.max(1);
+        self.permits = Semaphore::new(self.parallelism);
+        self
+    }
+
+    /// The page cap set with `max_pages`, for listings paginated by hand.
+    pub fn page_limit(&self) -> Option<usize> {
+        self.max_pages
+    }
+
+    pub fn max_parallelism(&self) -> usize {
+        self.parallelism
+    }
+
+    /// Absolute URL of a REST API `path` such as `/repositories`.
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{}", self.api_url, path)
+    }
+
+    /// Whether GraphQL queries can be answered. GitHub only accepts them with
+    /// credentials, but replayed and offline cached responses need none.
+    pub fn can_query_graphql(&self) -> bool {
+        !self.tokens.is_anonymous()
+            || self
+                .fixtures
+                .as_ref()
+                .is_some_and(|fixtures| fixtures.mode() == Mode::Replay)
+            || self.cache.as_ref().is_some_and(Cache::is_offline)
+    }
+
+    /// Remaining budget for `resource` across all tokens, if we've seen a
+    /// response for it yet.
+    pub fn budget(&self, resource: &str) -> Option<Budget> {
+        self.tokens.budget(resource)
+    }
+
+    /// Requests that permanently failed since the last call.
+    pub fn take_failures(&self) -> Vec<Failure> {
+        std::mem::take(&mut *self.failures.lock().unwrap())
+    }
+
+    /// Rate limit points spent on GraphQL queries so far.
+    pub fn graphql_cost(&self) -> u64 {
+        self.graphql_cost.load(Ordering::Relaxed)
+    }
+
+    /// Listings that were cut short by the page cap since the last call.
+    pub fn take_truncations(&self) -> Vec<Truncation> {
+        std::mem::take(&mut *self.truncations.lock().unwrap())
+    }
+
+    pub async fn get_json<T: DeserializeOwned>(&self, url: &str) -> Result<T, FetchError> {
+        self.get_page(url, None).await.map(|(payload, _)| payload)
+    }
+
+    /// Run a GraphQL `query` and return its `data`. A response with errors
+    /// and no data counts as a failure.
+    pub async fn graphql<T: DeserializeOwned>(
+        &self,
+        query: &str,
+        variables: serde_json::Value,
+    ) -> Result<T, FetchError> {
+        let body = serde_json::json!({ "query": query, "variables": variables }).to_string();
+        let request = Request::post(&self.graphql_url, &body);
+
+        let result = self.get_with_retries(request).await.and_then(|page| {
+            if let Ok(GraphqlResponse {
+                data:
+                    Some(GraphqlData {
+                        rate_limit: Some(rate_limit),
+                    }),
+                ..
+            }) = serde_json::from_str(&page.body)
+            {
+                let total = self
+                    .graphql_cost
+                    .fetch_add(rate_limit.cost, Ordering::Relaxed);
+                println!(
+                    "GraphQL query cost {} points ({} spent, {} remaining)",
+                    rate_limit.cost,
+                    total + rate_limit.cost,
+                    rate_limit.remaining
+                );
+            }
+
+            let response: GraphqlResponse<T> =
+                serde_json::from_str(&page.body).map_err(FetchError::Decode)?;
+            let messages = response
+                .errors
+                .iter()
+                .map(|error| error.message.as_str())
+                .collect::<Vec<_>>()
+                .join("; ");
+
+            match response.data {
+                Some(data) => {
+                    if !messages.is_empty() {
+                        println!("GraphQL query returned errors: {}", messages);
+                    }
+                    Ok(data)
+                }
+                None => Err(FetchError::Graphql(messages)),
+            }
+        });
+
+        self.record_failure(request, &result);
+        result
+    }
+
+    /// Every page of the listing at `url`, until there are none left or the
+    /// page cap is reached. When GitHub tells us which page is the last one,
+    /// the remaining pages are fetched concurrently; otherwise we follow the
+    /// `rel="next"` links one by one. Pages that can't be fetched are left
+    /// out and recorded like any other failure.
+    pub fn paginate<'a, T>(&'a self, url: &str) -> BoxStream<'a, T>
+    where
+        T: DeserializeOwned + Send + 'a,
+    {
+        self.paginate_with_accept(url, None)
+    }
+
+    /// Same as `paginate`, asking for the `accept` media type instead of the
+    /// default one.
+    pub fn paginate_with_accept<'a, T>(
+        &'a self,
+        url: &str,
+        accept: Option<&'a str>,
+    ) -> BoxStream<'a, T>
+    where
+        T: DeserializeOwned + Send + 'a,
+    {
+        let url = url.to_string();
+
+        stream::once(async move {
+            let first = self.get_page::<T>(&url, accept).await;
+            (url, first)
+        })
+        .flat_map(move |(url, first)| match first {
+            Ok((payload, links)) => {
+                let rest = match links.last.as_deref().and_then(last_page) {
+                    Some((last, page)) => self.fetch_pages(url, accept, last, page),
+                    None => self.follow_next(url, accept, links.next),
+                };
+                stream::once(future::ready(payload)).chain(rest).boxed()
+            }
+            Err(_) => stream::empty().boxed(),
+        })
+        .boxed()
+    }
+
+    /// Pages 2 to `last_page` of the listing at `url`, given the URL of the
+    /// last one.
+    fn fetch_pages<'a, T>(
+        &'a self,
+        url: String,
+        accept: Option<&'a str>,
+        last: Url,
+        last_page: usize,
+    ) -> BoxStream<'a, T>
+    where
+        T: DeserializeOwned + Send + 'a,
+    {
+        let pages = match self.max_pages {
+            Some(max_pages) if max_pages < last_page => {
+                self.record_truncation(url, max_pages);
+                max_pages
+            }
+            _ => last_page,
+        };
+
+        stream::iter(2..=pages)
+            .map(move |page| {
+                let url = with_page(&last, page);
+                async move { self.get_page::<T>(&url, accept).await.ok() }
+            })
+            .buffered(self.parallelism)
+            .filter_map(|page| future::ready(page.map(|(payload, _)| payload)))
+            .boxed()
+    }
+
+    /// Every page after the first of the listing at `url`, following `next`
+    /// links until there are none left.
+    fn follow_next<'a, T>(
+        &'a self,
+        url: String,
+        accept: Option<&'a str>,
+        next: Option<String>,
+    ) -> BoxStream<'a, T>
+    where
+        T: DeserializeOwned + Send + 'a,
+    {
+        stream::unfold((next, 1), move |(next, pages)| {
+            let url = url.clone();
+            async move {
+                let next = next?;
+
+                if self.max_pages.is_some_and(|max_pages| pages >= max_pages) {
+                    self.record_truncation(url, pages);
+                    return None;
+                }
+
+                let (payload, links) = self.get_page(&next, accept).await.ok()?;
+                Some((payload, (links.next, pages + 1)))
+            }
+        })
+        .boxed()
+    }
+
+    pub fn record_truncation(&self, url: String, pages: usize) {
+        println!("Stopping after {} pages: {}", pages, url);
+        self.truncations
+            .lock()
+            .unwrap()
+            .push(Truncation { url, pages });
+    }
+
+    async fn get_page<T: DeserializeOwned>(
+        &self,
+        url: &str,
+        accept: Option<&str>,
+    ) -> Result<(T, Links), FetchError> {
+        let request = Request::get(url).accept(accept);
+        let result = self.get_with_retries(request).await.and_then(|page| {
+            let payload = serde_json::from_str(&page.body).map_err(FetchError::Decode)?;
+            Ok((payload, page.links))
+        });
+
+        self.record_failure(request, &result);
+        result
+    }
+
+    fn record_failure<T>(&self, request: Request<'_>, result: &Result<T, FetchError>) {
+        if let Err(err) = result {
+            println!("Giving up on {}: {}", request.url, err);
+            self.failures.lock().unwrap().push(Failure {
+                url: request.key(),
+                status: err.status().map(|status| status.as_u16()),
+                error: err.to_string(),
+            });
+        }
+    }
+
+    async fn get_with_retries(&self, request: Request<'_>) -> Result<Page, FetchError> {
+        let cached = match &self.cache {
+            Some(cache) => cache.get(&request.key()).await,
+            None => None,
+        };
+
+        if self.cache.as_ref().is_some_and(Cache::is_offline) {
+            return cached.map(Page::from).ok_or(FetchError::NotCached);
+        }
+
+        let mut attempt = 1;
+
+        loop {
+            match self.fetch(request, cached.as_ref()).await {
+                Err(err) if err.is_retryable() && attempt < self.max_attempts => {
+                    let delay = retry_delay(attempt);
+                    println!(
+                        "Attempt {}/{} failed, retrying in {}s: {}",
+                        attempt,
+                        self.max_attempts,
+                        delay.as_secs(),
+                        err
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Send `request`, unless `cached` is still current, and keep whatever
+    /// came back in the cache.
+    async fn fetch(
+        &self,
+        request: Request<'_>,
+        cached: Option<&CachedResponse>,
+    ) -> Result<Page, FetchError> {
+        let response = self.send(request, cached).await?;
+
+        if response.status() == StatusCode::NOT_MODIFIED {
+            if let Some(cached) = cached {
+                return Ok(Page::from(cached.clone()));
+            }
+        }
+
+        let headers = response.headers().clone();
+        let links = Links::from_headers(&headers);
+        let body = response.text().await.map_err(FetchError::Request)?;
+
+        if let Some(cache) = &self.cache {
+            cache
+                .put(
+                    &request.key(),
+                    &CachedResponse {
+                        url: request.url.to_string(),
+                        etag: header_value(&headers, ETAG.as_str()),
+                        last_modified: header_value(&headers, LAST_MODIFIED.as_str()),
+                        next: links.next.clone(),
+                        last: links.last.clone(),
+                        body: body.clone(),
+                    },
+                )
+                .await;
+        }
+
+        Ok(Page { body, links })
+    }
+
+    async fn send(
+        &self,
+        request: Request<'_>,
+        cached: Option<&CachedResponse>,
+    ) -> Result<Response, FetchError> {
+        let resource = resource_for(request.url);
+        let mut rate_limited = 0;
+
+        loop {
+            let token = self.tokens.acquire(resource).await;
+            let permit = self.permits.acquire().await.expect("semaphore closed");
+
+            let response = match &self.fixtures {
+                Some(fixtures) if fixtures.mode() == Mode::Replay => fixtures
+                    .replay(request)
+                    .await
+                    .ok_or(FetchError::NotRecorded)?,
+                Some(fixtures) => {
+                    let response = self.request(request, token, cached).await?.send().await;
+                    fixtures
+                        .record(request, response.map_err(FetchError::Request)?)
+                        .await
+                        .map_err(FetchError::Request)?
+                }
+                None => self
+                    .request(request, token, cached)
+                    .await?
+                    .send()
+                    .await
+                    .map_err(FetchError::Request)?,
+            };
+
+            drop(permit);
+
+            let headers = response.headers().clone();
+            if let Some(budget) = Budget::from_headers(&headers) {
+                let resource = headers
+                    .get("x-ratelimit-resource")
+                    .and_then(|value| value.to_str().ok())
+                    .unwrap_or(resource);
+                token.update_budget(resource, budget);
+            }
+
+            let status = response.status();
+            if status.is_success() || status == StatusCode::NOT_MODIFIED {
+                return Ok(response);
+            }
+
+            let body = response.text().await.unwrap_or_default();
+            match rate_limit_wait(status, &headers, &body) {
+                Some(wait) if rate_limited < MAX_RATE_LIMIT_RETRIES => {
+                    rate_limited += 1;
+                    println!(
+                        "Rate limited, retrying in {}s: {}",
+                        wait.as_secs(),
+                        request.url
+                    );
+                    tokio::time::sleep(wait).await;
+                }
+                _ => return Err(FetchError::Status(status, body)),
+            }
+        }
+    }
+
+    async fn request(
+        &self,
+        request: Request<'_>,
+        token: &Token,
+        cached: Option<&CachedResponse>,
+    ) -> Result<reqwest::RequestBuilder, FetchError> {
+        let authorization = token.authorization(&self.client, &self.api_url).await?;
+
+        let mut builder = match request.body {
+            Some(body) => self
+                .client
+                .post_github(request.url, authorization.as_deref())
+                .body(body.to_string()),
+            None => self
+                .client
+                .get_github(request.url, authorization.as_deref()),
+        };
+        if let Some(accept) = request.accept {
+            // `headers` replaces the default `Accept` instead of adding to it.
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                ACCEPT,
+                HeaderValue::from_str(accept).expect("invalid media type"),
+            );
+            builder = builder.headers(headers);
+        }
+        if let Some(etag) = cached.and_then(|cached| cached.etag.as_ref()) {
+            builder = builder.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = cached.and_then(|cached| cached.last_modified.as_ref()) {
+            builder = builder.header(IF_MODIFIED_SINCE, last_modified);
+        }
+        Ok(builder)
+    }
+}
+
+/// Exponential backoff with "equal jitter": half of the delay is fixed, the
+/// other half random, so concurrent retries don't all fire at once.
+fn retry_delay(attempt: u32) -> Duration {
+    let delay = BASE_RETRY_DELAY
+        .saturating_mul(2u32.saturating_pow(attempt - 1))
+        .min(MAX_RETRY_DELAY);
+    delay / 2 + delay.mul_f64(rand::random::<f64>() / 2.0)
+}
+
+/// If a failed response is GitHub telling us to slow down, how long to wait
+/// before trying again.
+fn rate_limit_wait(status: StatusCode, headers: &HeaderMap, body: &str) -> Option<Duration> {
+    if status != StatusCode::FORBIDDEN && status != StatusCode::TOO_MANY_REQUESTS {
+        return None;
+    }
+
+    if let Some(seconds) = header_value::<u64>(headers, RETRY_AFTER.as_str()) {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    // Wait until the budget is refilled, and at least a second, in case our
+    // clock is ahead of GitHub's and the reset looks like it already passed.
+    if let Some(budget) = Budget::from_headers(headers) {
+        if budget.remaining == 0 {
+            return Some(budget.time_until_reset());
+        }
+    }
+
+    if status == StatusCode::TOO_MANY_REQUESTS || body.contains("rate limit") {
+        return Some(SECONDARY_LIMIT_WAIT);
+    }
+
+    None
+}
+
+/// The GraphQL endpoint of the instance whose REST API is at `api_url`:
+/// `https://api.github.com/graphql` for GitHub itself and
+/// `https://HOST/api/graphql` for GitHub Enterprise Server.
+fn graphql_url_for(api_url: &str) -> String {
+    match api_url.strip_suffix("/api/v3") {
+        Some(host) => format!("{}/api/graphql", host),
+        None => format!("{}/graphql", api_url),
+    }
+}
+
+/// The rate limit resource a request to `url` is billed against.
+fn resource_for(url: &str) -> &'static str {
+    if url.contains("/search/") {
+        "search"
+    } else if url.ends_with("/graphql") {
+        "graphql"
+    } else {
+        "core"
+    }
+}
+
+impl Links {
+    /// Parses a `Link` header like
+    /// `<https://api.github.com/...&page=2>; rel="next", <...>; rel="last"`.
+    fn from_headers(headers: &HeaderMap) -> Links {
+        let mut links = Links::default();
+        let Some(link) = headers.get(LINK).and_then(|link| link.to_str().ok()) else {
+            return links;
+        };
+
+        for part in link.split(',') {
+            let Some((target, params)) = part.split_once(';') else {
+                continue;
+            };
+            let target = target
+                .trim()
+                .trim_start_matches('<')
+                .trim_end_matches('>')
+                .to_string();
+
+            for param in params.split(';').map(str::trim) {
+                match param {
+                    r#"rel="next""# => links.next = Some(target.clone()),
+                    r#"rel="last""# => links.last = Some(target.clone()),
+                    _ => {}
+                }
+            }
+        }
+
+        links
+    }
+}
+
+/// The parsed URL of the last page of a listing and its page number.
+fn last_page(last: &str) -> Option<(Url, usize)> {
+    let url = Url::parse(last).ok()?;
+    let page = url
+        .query_pairs()
+        .find(|(name, _)| name == "page")?
+        .1
+        .parse()
+        .ok()?;
+    Some((url, page))
+}
+
+/// `url` with its `page` query parameter set to `page`.
+fn with_page(url: &Url, page: usize) -> String {
+    let mut url = url.clone();
+    let pairs: Vec<(String, String)> = url
+        .query_pairs()
+        .map(|(name, value)| {
+            let value = if name == "page" {
+                page.to_string()
+            } else {
+                value.into_owned()
+            };
+            (name.into_owned(), value)
+        })
+        .collect();
+    url.query_pairs_mut().clear().extend_pairs(pairs);
+    url.to_string()
+}
+
+fn header_value<T: std::str::FromStr>(headers: &HeaderMap, name: &str) -> Option<T> {
+    headers.get(name)?.to_str().ok()?.parse().ok()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|&(name, value)| {
+                (
+                    reqwest::header::HeaderName::from_static(name),
+                    HeaderValue::from_str(value).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn links_are_parsed_from_the_link_header() {
+        let links = Links::from_headers(&headers(&[(
+            "link",
+            r#"<https://api.github.com/repositories/1/issues?page=2>; rel="next", <https://api.github.com/repositories/1/issues?page=5>; rel="last""#,
+        )]));
+
+        assert_eq!(
+            links.next.as_deref(),
+            Some("https://api.github.com/repositories/1/issues?page=2")
+        );
+        assert_eq!(
+            links.last.as_deref(),
+            Some("https://api.github.com/repositories/1/issues?page=5")
+        );
+    }
+
+    #[test]
+    fn links_are_empty_without_a_link_header() {
+        let links = Links::from_headers(&HeaderMap::new());
+
+        assert!(links.next.is_none());
+        assert!(links.last.is_none());
+    }
+
+    #[test]
+    fn last_page_reads_the_page_parameter() {
+        let (url, page) = last_page("https://api.github.com/issues?per_page=100&page=7").unwrap();
+
+        assert_eq!(page, 7);
+        assert_eq!(url.path(), "/issues");
+        assert!(last_page("https://api.github.com/issues?per_page=100").is_none());
+        assert!(last_page("not a url").is_none());
+    }
+
+    #[test]
+    fn with_page_replaces_only_the_page_parameter() {
+        let url =
+            Url::parse("https://api.github.com/issues?per_page=100&page=7&state=closed").unwrap();
+
+        assert_eq!(
+            with_page(&url, 3),
+            "https://api.github.com/issues?per_page=100&page=3&state=closed"
+        );
+    }
+
+    #[test]
+    fn retry_delay_doubles_with_jitter_up_to_the_maximum() {
+        for attempt in 1..5 {
+            let delay = BASE_RETRY_DELAY * 2u32.pow(attempt - 1);
+            let retry = retry_delay(attempt);
+            assert!(delay / 2 <= retry && retry <= delay, "{:?}", retry);
+        }
+
+        let retry = retry_delay(20);
+        assert!(MAX_RETRY_DELAY / 2 <= retry && retry <= MAX_RETRY_DELAY);
+    }
+
+    #[test]
+    fn rate_limit_wait_honors_retry_after() {
+        let wait = rate_limit_wait(
+            StatusCode::FORBIDDEN,
+            &headers(&[("retry-after", "30")]),
+            "",
+        );
+
+        assert_eq!(wait, Some(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn rate_limit_wait_lasts_until_exhausted_budgets_reset() {
+        let reset = (unix_now() + 30).to_string();
+        let exhausted = headers(&[
+            ("x-ratelimit-limit", "5000"),
+            ("x-ratelimit-remaining", "0"),
+            ("x-ratelimit-reset", &reset),
+        ]);
+
+        let wait = rate_limit_wait(StatusCode::FORBIDDEN, &exhausted, "").unwrap();
+        assert!(Duration::from_secs(30) <= wait && wait <= Duration::from_secs(31));
+    }
+
+    #[test]
+    fn rate_limit_wait_is_never_zero_when_the_reset_looks_past() {
+        let exhausted = headers(&[
+            ("x-ratelimit-limit", "5000"),
+            ("x-ratelimit-remaining", "0"),
+            ("x-ratelimit-reset", "1700000000"),
+        ]);
+
+        assert_eq!(
+            rate_limit_wait(StatusCode::FORBIDDEN, &exhausted, ""),
+            Some(Duration::from_secs(1))
+        );
+    }
+
+    #[test]
+    fn rate_limit_wait_backs_off_on_secondary_limits() {
+        assert_eq!(
+            rate_limit_wait(StatusCode::TOO_MANY_REQUESTS, &HeaderMap::new(), ""),
+            Some(SECONDARY_LIMIT_WAIT)
+        );
+        assert_eq!(
+            rate_limit_wait(
+                StatusCode::FORBIDDEN,
+                &HeaderMap::new(),
+                "You have exceeded a secondary rate limit"
+            ),
+            Some(SECONDARY_LIMIT_WAIT)
+        );
+    }
+
+    #[test]
+    fn rate_limit_wait_ignores_other_failures() {
+        assert_eq!(
+            rate_limit_wait(
+                StatusCode::FORBIDDEN,
+                &HeaderMap::new(),
+                "Resource not accessible"
+            ),
+            None
+        );
+        assert_eq!(
+            rate_limit_wait(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &headers(&[("retry-after", "30")]),
+                ""
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn graphql_url_follows_the_api_url() {
+        assert_eq!(
+            graphql_url_for(DEFAULT_API_URL),
+            "https://api.github.com/graphql"
+        );
+        assert_eq!(
+            graphql_url_for("https://github.example.com/api/v3"),
+            "https://github.example.com/api/graphql"
+        );
+    }
+}
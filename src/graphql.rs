@@ -0,0 +1,687 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Deserializer};
+use serde_json::{json, Value};
+
+use crate::github::Github;
+use crate::{
+    Comment, CommentRevision, Issue, IssueFilter, Label, Minimization, PullRequestLinks, Reactions,
+    Repository, User,
+};
+
+/// How many issues' comments are requested in a single query.
+const COMMENTS_BATCH_SIZE: usize = 20;
+
+/// How many comments are looked up by id in a single query.
+const NODES_BATCH_SIZE: usize = 100;
+
+const MINIMIZATIONS_QUERY: &str = r#"
+query($ids: [ID!]!) {
+  rateLimit { cost remaining }
+  nodes(ids: $ids) {
+    ... on Minimizable { isMinimized minimizedReason }
+  }
+}
+"#;
+
+/// How many comments' edit histories are requested in a single query.
+const REVISIONS_BATCH_SIZE: usize = 50;
+
+/// A page of the edit history of a comment, selected for every comment of a
+/// `revisions_query`.
+const EDITS_FRAGMENT: &str = r#"
+fragment Edits on UserContentEditConnection {
+  pageInfo { hasNextPage endCursor }
+  nodes { id editedAt deletedAt editor { login } diff }
+}
+"#;
+
+/// Fields read from both issues and pull requests. A fragment can't be
+/// spread on both types, so the selection is pasted into each query instead.
+macro_rules! issue_fields {
+    () => {
+        "fullDatabaseId number title body createdAt locked activeLockReason state \
+         author { login ... on User { databaseId } ... on Bot { databaseId } } \
+         labels(first: 100) { nodes { name } }"
+    };
+}
+
+const ISSUES_QUERY: &str = concat!(
+    r#"
+query($owner: String!, $name: String!, $cursor: String, $states: [IssueState!]) {
+  rateLimit { cost remaining }
+  repository(owner: $owner, name: $name) {
+    issues(first: 100, after: $cursor, states: $states) {
+      pageInfo { hasNextPage endCursor }
+      nodes { "#,
+    issue_fields!(),
+    r#" }
+    }
+  }
+}
+"#
+);
+
+/// Aliased to `issues` so the response reads the same as `ISSUES_QUERY`'s.
+const PULL_REQUESTS_QUERY: &str = concat!(
+    r#"
+query($owner: String!, $name: String!, $cursor: String, $states: [PullRequestState!]) {
+  rateLimit { cost remaining }
+  repository(owner: $owner, name: $name) {
+    issues: pullRequests(first: 100, after: $cursor, states: $states) {
+      pageInfo { hasNextPage endCursor }
+      nodes { "#,
+    issue_fields!(),
+    r#" }
+    }
+  }
+}
+"#
+);
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct PageInfo {
+    has_next_page: bool,
+    end_cursor: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Connection<T> {
+    #[serde(rename = "pageInfo")]
+    page_info: PageInfo,
+    nodes: Vec<T>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct IssueNode {
+    #[serde(deserialize_with = "deserialize_big_int")]
+    full_database_id: i64,
+    number: i64,
+    title: String,
+    created_at: String,
+    locked: bool,
+    active_lock_reason: Option<String>,
+    state: String,
+    body: String,
+    author: Option<AuthorNode>,
+    labels: Option<Nodes<LabelNode>>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct AuthorNode {
+    login: String,
+    database_id: Option<i64>,
+}
+
+impl AuthorNode {
+    /// Authors without a database id, like mannequins, are left out.
+    fn into_user(self) -> Option<User> {
+        Some(User {
+            login: self.login,
+            id: self.database_id?,
+        })
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct LabelNode {
+    name: String,
+}
+
+/// A connection read without paginating it.
+#[derive(Deserialize, Debug)]
+struct Nodes<T> {
+    nodes: Vec<T>,
+}
+
+#[derive(Deserialize, Debug)]
+struct RepositoryIssues {
+    issues: Connection<IssueNode>,
+}
+
+#[derive(Deserialize, Debug)]
+struct IssuesData {
+    repository: Option<RepositoryIssues>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct CommentNode {
+    id: String,
+    #[serde(deserialize_with = "deserialize_big_int")]
+    full_database_id: i64,
+    body: String,
+    created_at: String,
+    updated_at: String,
+    author: Option<AuthorNode>,
+    author_association: String,
+    reaction_groups: Option<Vec<ReactionGroup>>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ReactionGroup {
+    content: String,
+    reactors: TotalCount,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct TotalCount {
+    total_count: i64,
+}
+
+#[derive(Deserialize, Debug)]
+struct IssueComments {
+    comments: Connection<CommentNode>,
+}
+
+#[derive(Deserialize, Debug)]
+struct RepositoryIssue {
+    #[serde(rename = "issueOrPullRequest")]
+    issue: Option<IssueComments>,
+}
+
+#[derive(Deserialize, Debug)]
+struct MinimizationsData {
+    nodes: Vec<Option<MinimizableNode>>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct MinimizableNode {
+    is_minimized: Option<bool>,
+    minimized_reason: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct EditableNode {
+    user_content_edits: Option<Connection<EditNode>>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct EditNode {
+    id: String,
+    edited_at: String,
+    deleted_at: Option<String>,
+    editor: Option<EditorNode>,
+    /// Despite the name, the whole text of this version.
+    diff: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct EditorNode {
+    login: String,
+}
+
+/// An issue whose comments are still being fetched.
+struct PendingIssue {
+    id_issue: i64,
+    comments_url: String,
+    owner: String,
+    name: String,
+    number: i64,
+    cursor: Option<String>,
+    pages: usize,
+}
+
+/// A comment whose edit history is still being fetched.
+struct PendingComment {
+    id_comment: i64,
+    kind: String,
+    node_id: String,
+    cursor: Option<String>,
+    pages: usize,
+}
+
+/// Same as the REST `search_locked_issues`, 100 issues or pull requests per
+/// query.
+pub async fn search_locked_issues(
+    client: &Github,
+    repository: &Repository,
+    filter: &IssueFilter,
+) -> HashSet<Issue> {
+    let mut issues = HashSet::new();
+
+    println!("Searching issues (GraphQL): {}", repository.full_name);
+    let states: Vec<String> = filter
+        .states
+        .iter()
+        .map(|state| state.to_uppercase())
+        .collect();
+    search_connection(client, repository, filter, false, &states, &mut issues).await;
+
+    println!(
+        "Searching pull requests (GraphQL): {}",
+        repository.full_name
+    );
+    let mut states = states;
+    if states.iter().any(|state| state == "CLOSED") {
+        states.push("MERGED".to_string());
+    }
+    search_connection(client, repository, filter, true, &states, &mut issues).await;
+
+    issues
+}
+
+/// Every page of the repository's issues, or pull requests, in one of
+/// `states`, keeping those matching `filter`.
+async fn search_connection(
+    client: &Github,
+    repository: &Repository,
+    filter: &IssueFilter,
+    is_pull_request: bool,
+    states: &[String],
+    issues: &mut HashSet<Issue>,
+) {
+    let Some((owner, name)) = repository.full_name.split_once('/') else {
+        return;
+    };
+    let query = if is_pull_request {
+        PULL_REQUESTS_QUERY
+    } else {
+        ISSUES_QUERY
+    };
+
+    let mut cursor: Option<String> = None;
+    let mut pages = 0;
+
+    loop {
+        if client
+            .page_limit()
+            .is_some_and(|max_pages| pages >= max_pages)
+        {
+            client.record_truncation(repository.issues_url.clone(), pages);
+            break;
+        }
+
+        let variables = json!({ "owner": owner, "name": name, "cursor": cursor, "states": states });
+        let data: IssuesData = match client.graphql(query, variables).await {
+            Ok(data) => data,
+            Err(_) => break,
+        };
+        let Some(repository_issues) = data.repository else {
+            break;
+        };
+        pages += 1;
+
+        let locked_issues = repository_issues
+            .issues
+            .nodes
+            .into_iter()
+            .map(|node| Issue {
+                id: node.full_database_id,
+                number: node.number,
+                title: node.title,
+                // REST leaves out the body of issues without one, GraphQL
+                // sends it empty.
+                body: Some(node.body).filter(|body| !body.is_empty()),
+                user: node.author.and_then(AuthorNode::into_user),
+                labels: node
+                    .labels
+                    .map(|labels| labels.nodes)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|label| Label { name: label.name })
+                    .collect(),
+                created_at: node.created_at,
+                repository_id: Some(repository.id),
+                comments_url: client.url(&format!(
+                    "/repos/{}/issues/{}/comments",
+                    repository.full_name, node.number
+                )),
+                locked: node.locked,
+                active_lock_reason: node.active_lock_reason.as_deref().map(lock_reason),
+                // REST reports merged pull requests as closed.
+                state: match node.state.as_str() {
+                    "MERGED" => "closed".to_string(),
+                    state => state.to_lowercase(),
+                },
+                pull_request: is_pull_request.then(|| PullRequestLinks {
+                    url: client.url(&format!(
+                        "/repos/{}/pulls/{}",
+                        repository.full_name, node.number
+                    )),
+                }),
+            })
+            .filter(|issue| filter.matches(issue));
+
+        issues.extend(locked_issues);
+
+        let page_info = repository_issues.issues.page_info;
+        if !page_info.has_next_page {
+            break;
+        }
+        cursor = page_info.end_cursor;
+    }
+}
+
+/// Every comment of the given `(id_issue, comments_url)` issues, fetched for
+/// several issues per query.
+pub async fn fetch_comments(client: &Github, issues: &[(i64, String)]) -> HashSet<Comment> {
+    let mut pending: Vec<PendingIssue> = issues
+        .iter()
+        .filter_map(|(id_issue, comments_url)| {
+            let (owner, name, number) = parse_comments_url(comments_url)?;
+            Some(PendingIssue {
+                id_issue: *id_issue,
+                comments_url: comments_url.clone(),
+                owner,
+                name,
+                number,
+                cursor: None,
+                pages: 0,
+            })
+        })
+        .collect();
+
+    let mut comments = HashSet::new();
+
+    while !pending.is_empty() {
+        let batch: Vec<PendingIssue> = pending
+            .drain(..pending.len().min(COMMENTS_BATCH_SIZE))
+            .collect();
+        println!("Retrieving Comments (GraphQL) for {} issues", batch.len());
+
+        let (query, variables) = comments_query(&batch);
+        let mut data: HashMap<String, Value> = match client.graphql(&query, variables).await {
+            Ok(data) => data,
+            Err(_) => continue,
+        };
+
+        for (index, mut issue) in batch.into_iter().enumerate() {
+            let Some(issue_comments) = data
+                .remove(&format!("issue{}", index))
+                .and_then(|value| serde_json::from_value::<RepositoryIssue>(value).ok())
+                .and_then(|repository| repository.issue)
+            else {
+                continue;
+            };
+
+            comments.extend(
+                issue_comments
+                    .comments
+                    .nodes
+                    .into_iter()
+                    .map(|node| Comment {
+                        id: node.full_database_id,
+                        body: node.body,
+                        created_at: node.created_at,
+                        issue_id: Some(issue.id_issue),
+                        user: node.author.and_then(AuthorNode::into_user),
+                        author_association: Some(node.author_association),
+                        updated_at: Some(node.updated_at),
+                        reactions: node.reaction_groups.map(reactions),
+                        node_id: Some(node.id),
+                    }),
+            );
+
+            issue.pages += 1;
+            let page_info = issue_comments.comments.page_info;
+            if !page_info.has_next_page {
+                continue;
+            }
+
+            if client
+                .page_limit()
+                .is_some_and(|max_pages| issue.pages >= max_pages)
+            {
+                client.record_truncation(issue.comments_url, issue.pages);
+                continue;
+            }
+
+            issue.cursor = page_info.end_cursor;
+            pending.push(issue);
+        }
+    }
+
+    comments
+}
+
+/// Whether each of the given `(id_comment, kind, node_id)` comments is
+/// hidden, and why.
+pub async fn fetch_minimizations(
+    client: &Github,
+    comments: &[(i64, String, String)],
+) -> Vec<Minimization> {
+    let mut minimizations = Vec::new();
+
+    for batch in comments.chunks(NODES_BATCH_SIZE) {
+        println!(
+            "Retrieving hidden state (GraphQL) for {} comments",
+            batch.len()
+        );
+
+        let ids: Vec<&str> = batch
+            .iter()
+            .map(|(_, _, node_id)| node_id.as_str())
+            .collect();
+        let data: MinimizationsData = match client
+            .graphql(MINIMIZATIONS_QUERY, json!({ "ids": ids }))
+            .await
+        {
+            Ok(data) => data,
+            Err(_) => continue,
+        };
+
+        for ((id_comment, kind, _), node) in batch.iter().zip(data.nodes) {
+            let Some(MinimizableNode {
+                is_minimized: Some(is_minimized),
+                minimized_reason,
+            }) = node
+            else {
+                continue;
+            };
+
+            minimizations.push(Minimization {
+                id_comment: *id_comment,
+                kind: kind.clone(),
+                is_minimized,
+                reason: minimized_reason.map(|reason| reason.to_lowercase()),
+            });
+        }
+    }
+
+    minimizations
+}
+
+/// The edit history of the given `(id_comment, kind, node_id)` comments,
+/// several comments per query. Edits come newest first, 100 per page.
+pub async fn fetch_revisions(
+    client: &Github,
+    comments: &[(i64, String, String)],
+) -> Vec<CommentRevision> {
+    let mut pending: Vec<PendingComment> = comments
+        .iter()
+        .map(|(id_comment, kind, node_id)| PendingComment {
+            id_comment: *id_comment,
+            kind: kind.clone(),
+            node_id: node_id.clone(),
+            cursor: None,
+            pages: 0,
+        })
+        .collect();
+
+    let mut revisions = Vec::new();
+
+    while !pending.is_empty() {
+        let batch: Vec<PendingComment> = pending
+            .drain(..pending.len().min(REVISIONS_BATCH_SIZE))
+            .collect();
+        println!(
+            "Retrieving edit history (GraphQL) for {} comments",
+            batch.len()
+        );
+
+        let (query, variables) = revisions_query(&batch);
+        let mut data: HashMap<String, Value> = match client.graphql(&query, variables).await {
+            Ok(data) => data,
+            Err(_) => continue,
+        };
+
+        for (index, mut comment) in batch.into_iter().enumerate() {
+            let Some(edits) = data
+                .remove(&format!("comment{}", index))
+                .and_then(|value| serde_json::from_value::<EditableNode>(value).ok())
+                .and_then(|node| node.user_content_edits)
+            else {
+                continue;
+            };
+
+            revisions.extend(edits.nodes.into_iter().map(|edit| CommentRevision {
+                id: edit.id,
+                id_comment: comment.id_comment,
+                kind: comment.kind.clone(),
+                edited_at: edit.edited_at,
+                deleted_at: edit.deleted_at,
+                editor: edit.editor.map(|editor| editor.login),
+                body: edit.diff,
+            }));
+
+            comment.pages += 1;
+            if !edits.page_info.has_next_page {
+                continue;
+            }
+
+            if client
+                .page_limit()
+                .is_some_and(|max_pages| comment.pages >= max_pages)
+            {
+                client.record_truncation(comment.node_id, comment.pages);
+                continue;
+            }
+
+            comment.cursor = edits.page_info.end_cursor;
+            pending.push(comment);
+        }
+    }
+
+    revisions
+}
+
+/// A query fetching the next page of edits of every comment in `batch`,
+/// aliased as `comment0`, `comment1`, ...
+fn revisions_query(batch: &[PendingComment]) -> (String, Value) {
+    let mut parameters = Vec::new();
+    let mut fields = Vec::new();
+    let mut variables = serde_json::Map::new();
+
+    for (index, comment) in batch.iter().enumerate() {
+        parameters.push(format!("$id{i}: ID!, $cursor{i}: String", i = index));
+        fields.push(format!(
+            "comment{i}: node(id: $id{i}) {{ \
+               ... on IssueComment {{ userContentEdits(first: 100, after: $cursor{i}) {{ ...Edits }} }} \
+               ... on PullRequestReviewComment {{ userContentEdits(first: 100, after: $cursor{i}) {{ ...Edits }} }} \
+             }}",
+            i = index
+        ));
+
+        variables.insert(format!("id{}", index), json!(comment.node_id));
+        variables.insert(format!("cursor{}", index), json!(comment.cursor));
+    }
+
+    let query = format!(
+        "query({}) {{ rateLimit {{ cost remaining }} {} }} {}",
+        parameters.join(", "),
+        fields.join(" "),
+        EDITS_FRAGMENT
+    );
+
+    (query, Value::Object(variables))
+}
+
+/// A query fetching the next page of comments of every issue in `batch`,
+/// aliased as `issue0`, `issue1`, ...
+fn comments_query(batch: &[PendingIssue]) -> (String, Value) {
+    let mut parameters = Vec::new();
+    let mut fields = Vec::new();
+    let mut variables = serde_json::Map::new();
+
+    for (index, issue) in batch.iter().enumerate() {
+        parameters.push(format!(
+            "$owner{i}: String!, $name{i}: String!, $number{i}: Int!, $cursor{i}: String",
+            i = index
+        ));
+        fields.push(format!(
+            "issue{i}: repository(owner: $owner{i}, name: $name{i}) {{ \
+               issueOrPullRequest(number: $number{i}) {{ \
+                 ... on Issue {{ comments(first: 100, after: $cursor{i}) {{ {comments} }} }} \
+                 ... on PullRequest {{ comments(first: 100, after: $cursor{i}) {{ {comments} }} }} \
+               }} \
+             }}",
+            i = index,
+            comments = "pageInfo { hasNextPage endCursor } \
+                        nodes { id fullDatabaseId body createdAt updatedAt authorAssociation \
+                          author { login ... on User { databaseId } ... on Bot { databaseId } } \
+                          reactionGroups { content reactors { totalCount } } }"
+        ));
+
+        variables.insert(format!("owner{}", index), json!(issue.owner));
+        variables.insert(format!("name{}", index), json!(issue.name));
+        variables.insert(format!("number{}", index), json!(issue.number));
+        variables.insert(format!("cursor{}", index), json!(issue.cursor));
+    }
+
+    let query = format!(
+        "query({}) {{ rateLimit {{ cost remaining }} {} }}",
+        parameters.join(", "),
+        fields.join(" ")
+    );
+
+    (query, Value::Object(variables))
+}
+
+/// Owner, name and issue number from a comments URL like
+/// `https://api.github.com/repos/OWNER/NAME/issues/NUMBER/comments`.
+fn parse_comments_url(comments_url: &str) -> Option<(String, String, i64)> {
+    let (_, path) = comments_url.split_once("/repos/")?;
+    let mut segments = path.split('/');
+
+    let owner = segments.next()?.to_string();
+    let name = segments.next()?.to_string();
+    let _issues = segments.next()?;
+    let number = segments.next()?.parse().ok()?;
+
+    Some((owner, name, number))
+}
+
+/// `fullDatabaseId` is a `BigInt`, which GitHub sends as a string since ids
+/// no longer fit in the 32 bit `databaseId`.
+fn deserialize_big_int<'de, D: Deserializer<'de>>(deserializer: D) -> Result<i64, D::Error> {
+    String::deserialize(deserializer)?
+        .parse()
+        .map_err(serde::de::Error::custom)
+}
+
+/// The REST reactions rollup from GraphQL `reactionGroups`.
+fn reactions(groups: Vec<ReactionGroup>) -> Reactions {
+    let mut reactions = Reactions::default();
+    for group in groups {
+        let count = group.reactors.total_count;
+        reactions.total_count += count;
+        match group.content.as_str() {
+            "THUMBS_UP" => reactions.plus_one = count,
+            "THUMBS_DOWN" => reactions.minus_one = count,
+            "LAUGH" => reactions.laugh = count,
+            "HOORAY" => reactions.hooray = count,
+            "CONFUSED" => reactions.confused = count,
+            "HEART" => reactions.heart = count,
+            "ROCKET" => reactions.rocket = count,
+            "EYES" => reactions.eyes = count,
+            _ => {}
+        }
+    }
+    reactions
+}
+
+/// REST spelling of a GraphQL `LockReason`, e.g. `TOO_HEATED` -> `too heated`.
+fn lock_reason(reason: &str) -> String {
+    match reason {
+        "OFF_TOPIC" => "off-topic".to_string(),
+        reason => reason.to_lowercase().replace('_', " "),
+    }
+}
@@ -1,60 +1,320 @@
-use std::collections::HashSet;
-use std::time::Duration;
-
-use csv;
-use reqwest::{
-    self,
-    header::{ACCEPT, AUTHORIZATION, USER_AGENT},
-    Client,
-};
+mod cache;
+mod churn;
+mod fixtures;
+mod gharchive;
+mod github;
+mod graphql;
+mod schema;
+mod search;
+mod strata;
+mod timeline;
+mod tokens;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use reqwest::Client;
 use serde::{Deserialize, Serialize};
-use sqlx::{sqlite::SqliteConnection, Connection, Row};
+use sqlx::{
+    sqlite::{SqliteConnection, SqliteRow},
+    Connection, Row,
+};
 use structopt::StructOpt;
 
-use chrono::{Days, NaiveDateTime};
+use chrono::{DateTime, Days, NaiveDate, NaiveDateTime, Utc};
+use futures_util::stream::{self, StreamExt};
 
-const GITUHB_REPO_URL: &str = "https://api.github.com/repositories";
+use cache::Cache;
+use fixtures::{Fixtures, Mode};
+use github::Github;
+use strata::{Strata, Stratum};
+use timeline::TimelineEvent;
+use tokens::{App, Token, TokenPool};
 
 #[derive(StructOpt, Debug)]
 struct Opts {
     #[structopt(short, long)]
     database_url: String,
-    #[structopt(short, long, required_unless_one = &["populate-comments", "generate-csv"])]
+    #[structopt(short, long, required_unless_one = &["populate-comments", "populate-revisions", "snapshot-repositories", "generate-csv", "export-comments", "churn", "discover", "gharchive"])]
     iterations: Option<u32>,
     #[structopt(long)]
     populate_comments: bool,
+    /// Fetch the edit history of every edited comment through GraphQL
+    #[structopt(long)]
+    populate_revisions: bool,
+    /// Store the current stars, forks, language, license, ... of every repository
+    #[structopt(long)]
+    snapshot_repositories: bool,
+    /// Also store the body of every issue as the first comment of its thread
+    #[structopt(long, requires = "populate-comments")]
+    opening_posts: bool,
     #[structopt(long)]
     generate_csv: bool,
+    /// Compare who contributed to each repository before and after its issues were locked
+    #[structopt(long)]
+    churn: bool,
+    /// Write every stored comment, with its labels, to comments.csv
+    #[structopt(long)]
+    export_comments: bool,
+    /// Find locked issues through the search API instead of sampling repositories
+    #[structopt(long)]
+    discover: bool,
+    /// Only discover issues created on or after this date (YYYY-MM-DD)
+    #[structopt(long, default_value = "2008-01-01", parse(try_from_str = parse_date))]
+    discover_since: DateTime<Utc>,
+    /// Only discover issues created before this date (YYYY-MM-DD), defaults to now
+    #[structopt(long, parse(try_from_str = parse_date))]
+    discover_until: Option<DateTime<Utc>>,
+    /// Build the dataset from these GH Archive dumps (.json.gz files or directories) without the API
+    #[structopt(long)]
+    gharchive: Vec<String>,
+    /// File with one GitHub token per line, in addition to GITHUB_TOKEN(S)
+    #[structopt(long)]
+    tokens_file: Option<String>,
+    /// ID of a GitHub App to authenticate as, together with --app-key and --installation-id
+    #[structopt(long, env = "GITHUB_APP_ID", requires_all = &["app-key", "installation-id"])]
+    app_id: Option<String>,
+    /// Path to the GitHub App's private key (PEM)
+    #[structopt(long, env = "GITHUB_APP_KEY", requires = "app-id")]
+    app_key: Option<String>,
+    /// Installation of the GitHub App to authenticate as; can be repeated
+    #[structopt(long, requires = "app-id")]
+    installation_id: Vec<u64>,
+    /// Send requests without a token, at GitHub's much lower rate limit
+    #[structopt(long)]
+    unauthenticated: bool,
+    /// Fetch issues and comments through the `rest` or `graphql` API
+    #[structopt(long, default_value = "rest", possible_values = &["rest", "graphql"])]
+    backend: Backend,
+    /// GraphQL endpoint, if it isn't the one belonging to --api-url
+    #[structopt(long, env = "GITHUB_GRAPHQL_URL")]
+    graphql_url: Option<String>,
+    /// Base URL of the REST API, e.g. to crawl a GitHub Enterprise Server
+    #[structopt(long, env = "GITHUB_API_URL", default_value = github::DEFAULT_API_URL)]
+    api_url: String,
+    /// How many times to try a request before recording it as failed
+    #[structopt(long, default_value = "5")]
+    max_attempts: u32,
+    /// How many requests to send to GitHub at once
+    #[structopt(long, default_value = "4")]
+    parallelism: usize,
+    /// Stop following paginated listings after this many pages
+    #[structopt(long)]
+    max_pages: Option<usize>,
+    /// Keep responses in this directory and only re-download them if they changed
+    #[structopt(long)]
+    cache_dir: Option<String>,
+    /// Serve every request from the cache directory without touching the network
+    #[structopt(long, requires = "cache-dir")]
+    offline: bool,
+    /// Write every GitHub response to this directory
+    #[structopt(long, conflicts_with = "replay")]
+    record: Option<String>,
+    /// Answer every GitHub request from responses recorded with --record
+    #[structopt(long)]
+    replay: Option<String>,
+    /// Sample repository ids up to this one instead of looking up the highest id
+    #[structopt(long)]
+    max_repo_id: Option<i64>,
+    /// Lock reasons of the issues to collect (comma separated)
+    #[structopt(
+        long,
+        use_delimiter = true,
+        default_value = "too heated",
+        possible_values = &["off-topic", "too heated", "resolved", "spam"]
+    )]
+    lock_reasons: Vec<String>,
+    /// States of the issues to collect (comma separated)
+    #[structopt(
+        long,
+        use_delimiter = true,
+        default_value = "closed",
+        possible_values = &["open", "closed"]
+    )]
+    states: Vec<String>,
+    /// Only sample repositories written in one of these languages (comma separated)
+    #[structopt(long, use_delimiter = true)]
+    languages: Vec<String>,
+    /// Lower bounds of the star count buckets to sample from, e.g. 0,10,100,1000
+    #[structopt(long, use_delimiter = true)]
+    star_buckets: Vec<i64>,
+    /// Only sample repositories created in one of these years (comma separated)
+    #[structopt(long, use_delimiter = true)]
+    creation_years: Vec<i32>,
+    /// Stop sampling a stratum (language, star bucket and year) after this many repositories
+    #[structopt(long)]
+    quota: Option<usize>,
+    /// Seed for repository sampling, so recorded crawls can be replayed
+    #[structopt(long)]
+    seed: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Backend {
+    Rest,
+    Graphql,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(backend: &str) -> Result<Self, Self::Err> {
+        match backend {
+            "rest" => Ok(Backend::Rest),
+            "graphql" => Ok(Backend::Graphql),
+            _ => Err(format!("unknown backend: {}", backend)),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Hash, Eq, PartialEq)]
 struct Repository {
-    id: i32,
+    id: i64,
     name: String,
+    full_name: String,
     forks_url: String,
     stargazers_url: String,
     commits_url: String,
     issues_url: String,
 }
 
+/// The parts of `/repos/{owner}/{name}` that `/repositories` leaves out.
+#[derive(Deserialize, Debug, Clone)]
+struct RepositoryMetadata {
+    language: Option<String>,
+    stargazers_count: i64,
+    forks_count: i64,
+    /// Users watching the repository; `watchers_count` is the star count.
+    subscribers_count: Option<i64>,
+    open_issues_count: i64,
+    #[serde(default)]
+    topics: Vec<String>,
+    license: Option<License>,
+    archived: bool,
+    owner: RepositoryOwner,
+    created_at: String,
+    pushed_at: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct License {
+    spdx_id: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct RepositoryOwner {
+    /// `User` or `Organization`
+    #[serde(rename = "type")]
+    kind: String,
+}
+
 #[derive(Serialize, Deserialize, Debug, Hash, Eq, PartialEq)]
 struct Issue {
-    id: i32,
+    id: i64,
+    number: i64,
     title: String,
+    body: Option<String>,
+    user: Option<User>,
+    #[serde(default)]
+    labels: Vec<Label>,
     created_at: String,
-    repository_id: Option<i32>,
+    repository_id: Option<i64>,
     comments_url: String,
     locked: bool,
     active_lock_reason: Option<String>,
     state: String,
+    /// Only set for pull requests, which the issues endpoints return as well.
+    #[serde(default)]
+    pull_request: Option<PullRequestLinks>,
+}
+
+impl Issue {
+    fn is_pull_request(&self) -> bool {
+        self.pull_request.is_some()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Hash, Eq, PartialEq)]
+struct PullRequestLinks {
+    url: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Hash, Eq, PartialEq)]
+struct User {
+    login: String,
+    id: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Hash, Eq, PartialEq)]
+struct Label {
+    name: String,
 }
 
 #[derive(Serialize, Deserialize, Debug, Hash, Eq, PartialEq)]
 struct Comment {
-    id: i32,
+    id: i64,
     body: String,
     created_at: String,
-    issue_id: Option<i32>,
+    issue_id: Option<i64>,
+    user: Option<User>,
+    /// `OWNER`, `MEMBER`, `CONTRIBUTOR`, `NONE`, ...
+    author_association: Option<String>,
+    updated_at: Option<String>,
+    reactions: Option<Reactions>,
+    node_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Hash, Eq, PartialEq)]
+struct Reactions {
+    total_count: i64,
+    #[serde(rename = "+1")]
+    plus_one: i64,
+    #[serde(rename = "-1")]
+    minus_one: i64,
+    laugh: i64,
+    hooray: i64,
+    confused: i64,
+    heart: i64,
+    rocket: i64,
+    eyes: i64,
+}
+
+/// Whether a maintainer hid a comment, and why (`abuse`, `spam`,
+/// `off-topic`, `outdated`, ...).
+#[derive(Debug)]
+struct Minimization {
+    id_comment: i64,
+    kind: String,
+    is_minimized: bool,
+    reason: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct CommentRow {
+    id_comment: i64,
+    kind: String,
+    id_issue: Option<i64>,
+    created_at: Option<String>,
+    user_login: Option<String>,
+    author_association: Option<String>,
+    is_toxic: bool,
+    is_minimized: Option<bool>,
+    minimized_reason: Option<String>,
+    text: String,
+}
+
+/// One version of a comment, from its GraphQL `userContentEdits`.
+#[derive(Debug)]
+struct CommentRevision {
+    id: String,
+    id_comment: i64,
+    kind: String,
+    edited_at: String,
+    deleted_at: Option<String>,
+    editor: Option<String>,
+    body: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Hash, Eq, PartialEq)]
@@ -74,6 +334,13 @@ struct CommitInfo {
 struct Commit {
     url: String,
     commit: CommitInfo,
+    /// The GitHub account the commit author's email belongs to, if any.
+    author: Option<CommitAuthor>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Hash, Eq, PartialEq)]
+struct CommitAuthor {
+    login: String,
 }
 
 #[derive(Serialize, Deserialize, Debug, Hash, Eq, PartialEq)]
@@ -81,129 +348,298 @@ struct CommitFlat {
     authored_date: String,
     committed_date: String,
     before_or_after: String,
-    id_issue: i32,
+    id_issue: i64,
 }
 
-trait GetGithub {
-    fn get_github(&self, url: &str) -> reqwest::RequestBuilder;
+/// Only returned with the `STAR_MEDIA_TYPE` media type.
+#[derive(Deserialize, Debug)]
+struct Stargazer {
+    starred_at: String,
 }
 
-impl GetGithub for reqwest::Client {
-    fn get_github(&self, url: &str) -> reqwest::RequestBuilder {
-        let github_token = std::env::var("GITHUB_TOKEN").unwrap();
-        self.get(url)
-            .header(ACCEPT, "application/vnd.github+json")
-            .header(USER_AGENT, "toxicity-metodologia")
-            .header(AUTHORIZATION, format!("Bearer {}", github_token))
-    }
+#[derive(Deserialize, Debug)]
+struct Fork {
+    created_at: String,
 }
 
-async fn get_repositories(client: &Client, url: &str) -> Vec<Repository> {
-    match client.get_github(url).send().await {
-        Ok(response) => response.json().await.unwrap_or(Vec::new()),
-        _ => Vec::new(),
-    }
+#[derive(Serialize, Debug)]
+struct StarOrForkFlat {
+    /// `star` or `fork`
+    kind: String,
+    date: String,
+    before_or_after: String,
+    id_issue: i64,
 }
 
-async fn search_too_heated_issues(client: &Client, repository: &Repository) -> HashSet<Issue> {
-    let issues_url = repository.issues_url.strip_suffix("{/number}").unwrap();
-    let mut issues = HashSet::new();
+/// Media type under which stargazers come with the date they starred.
+const STAR_MEDIA_TYPE: &str = "application/vnd.github.star+json";
 
-    for page in 1..50 {
-        let url = &format!("{}?page={}&per_page=100&state=closed", issues_url, page);
-        println!("Searching issues: {}", url);
+/// GitHub lists at most this many stargazers of a repository, oldest first,
+/// and its `rel="last"` link points at the last page it serves, so the most
+/// recent stars of popular repositories are silently left out.
+const MAX_STARGAZERS: usize = 40_000;
 
-        let response = {
-            match client.get_github(url).send().await {
-                Ok(response) => response,
-                _ => continue,
-            }
-        };
+async fn get_repositories(client: &Github, url: &str) -> Vec<Repository> {
+    client.get_json(url).await.unwrap_or_default()
+}
 
-        let issues_payload: Vec<Issue> = {
-            match response.json().await {
-                Ok(issues) => issues,
-                Err(_) => continue,
-            }
-        };
+async fn search_locked_issues(
+    client: &Github,
+    repository: &Repository,
+    filter: &IssueFilter,
+) -> HashSet<Issue> {
+    let issues_url = repository.issues_url.strip_suffix("{/number}").unwrap();
+    let mut issues = HashSet::new();
 
-        if issues_payload.is_empty() {
-            break;
-        }
+    let url = &format!("{}?per_page=100&state={}", issues_url, filter.state());
+    println!("Searching issues: {}", url);
 
-        let too_heated_issues = issues_payload
+    let mut pages = client.paginate::<Vec<Issue>>(url);
+    while let Some(issues_payload) = pages.next().await {
+        let locked_issues = issues_payload
             .into_iter()
-            .filter(|issues| {
-                issues.locked
-                    && issues.active_lock_reason == Some("too heated".to_string())
-                    && &issues.state == "closed"
-            })
+            .filter(|issue| filter.matches(issue))
             .map(|mut issue| {
                 issue.repository_id = Some(repository.id);
                 issue
             });
 
-        issues.extend(too_heated_issues);
-        std::thread::sleep(Duration::from_secs(5));
+        issues.extend(locked_issues);
     }
 
     issues
 }
 
-async fn populate_comments(conn: &mut SqliteConnection, client: &Client) {
-    let issues = sqlx::query("SELECT * FROM Issues")
+/// Which locked issues end up in the dataset.
+#[derive(Debug, Clone)]
+struct IssueFilter {
+    lock_reasons: Vec<String>,
+    states: Vec<String>,
+}
+
+impl IssueFilter {
+    fn matches(&self, issue: &Issue) -> bool {
+        issue.locked
+            && issue
+                .active_lock_reason
+                .as_ref()
+                .is_some_and(|reason| self.lock_reasons.contains(reason))
+            && self.states.contains(&issue.state)
+    }
+
+    /// The `state` parameter of the issues endpoint covering every state.
+    fn state(&self) -> &str {
+        match self.states.as_slice() {
+            [state] => state,
+            _ => "all",
+        }
+    }
+}
+
+async fn populate_comments(
+    conn: &mut SqliteConnection,
+    client: &Github,
+    backend: Backend,
+    opening_posts: bool,
+) {
+    let issues: Vec<(i64, String)> = sqlx::query("SELECT * FROM Issues ORDER BY id_issue")
         .fetch_all(&mut *conn)
         .await
-        .unwrap();
+        .unwrap()
+        .iter()
+        .map(|issue| (issue.get("id_issue"), issue.get("comments_url")))
+        .collect();
+
+    let comments = match backend {
+        Backend::Rest => fetch_comments(client, &issues).await,
+        Backend::Graphql => graphql::fetch_comments(client, &issues).await,
+    };
 
-    let mut comments = HashSet::new();
+    store_comments(conn, comments, "issue_comment").await;
 
-    for issue in issues.iter() {
-        let mut page = 1;
-        let comments_url: String = issue.get("comments_url");
-        let id_issue: i32 = issue.get("id_issue");
+    if opening_posts {
+        store_opening_posts(conn).await;
+    }
 
-        loop {
-            let url = &format!("{}?page={}&per_page=100", comments_url, page);
-            println!("Retrieving Comments: {}", url);
+    // Review comments on the diff of locked pull requests, only available
+    // through REST.
+    let pull_requests: Vec<(i64, String)> =
+        sqlx::query("SELECT id_issue, comments_url FROM Issues WHERE is_pull_request = 1")
+            .fetch_all(&mut *conn)
+            .await
+            .unwrap()
+            .iter()
+            .filter_map(|issue| {
+                let comments_url: String = issue.get("comments_url");
+                Some((issue.get("id_issue"), review_comments_url(&comments_url)?))
+            })
+            .collect();
+
+    let review_comments = fetch_comments(client, &pull_requests).await;
+    store_comments(conn, review_comments, "review_comment").await;
+
+    // Hidden comments are only reported by GraphQL, whichever backend
+    // fetched them. Batches are kept in the same order so replayed queries
+    // match the recorded ones.
+    if client.can_query_graphql() {
+        let minimizable: Vec<(i64, String, String)> =
+            sqlx::query("SELECT id_comment, kind, node_id FROM Comments WHERE node_id IS NOT NULL ORDER BY id_comment, kind")
+                .fetch_all(&mut *conn)
+                .await
+                .unwrap()
+                .iter()
+                .map(|comment| {
+                    (
+                        comment.get("id_comment"),
+                        comment.get("kind"),
+                        comment.get("node_id"),
+                    )
+                })
+                .collect();
+
+        let minimizations = graphql::fetch_minimizations(client, &minimizable).await;
+        store_minimizations(conn, minimizations).await;
+    } else {
+        println!("Skipping hidden comments, GraphQL needs a token");
+    }
 
-            let response = {
-                match client.get_github(url).send().await {
-                    Ok(response) => response,
-                    _ => continue,
-                }
-            };
+    let timelines = timeline::fetch_timelines(client, &issues).await;
+    store_lock_events(conn, &timelines).await;
+    store_timeline_events(conn, &timelines).await;
+}
 
-            let comments_payload: Vec<Comment> = {
-                match response.json().await {
-                    Ok(comments) => comments,
-                    Err(_) => continue,
-                }
-            };
+/// Every stored comment, with `is_toxic` and whether it was hidden as weak
+/// labels, in comments.csv.
+async fn export_comments(conn: &mut SqliteConnection) {
+    let mut writer = csv::Writer::from_path("comments.csv").unwrap();
 
-            if comments_payload.is_empty() {
-                break;
-            }
+    let comments = sqlx::query(
+        r#"
+        SELECT id_comment, kind, id_issue, created_at, user_login, author_association, is_toxic, is_minimized, minimized_reason, text
+          FROM Comments
+         ORDER BY id_issue, created_at
+        "#,
+    )
+    .fetch_all(&mut *conn)
+    .await
+    .unwrap();
 
-            let formated_comments = comments_payload.into_iter().map(|mut comment| {
-                comment.issue_id = Some(id_issue);
-                comment
-            });
+    for comment in comments {
+        let row = CommentRow {
+            id_comment: comment.get("id_comment"),
+            kind: comment.get("kind"),
+            id_issue: comment.get("id_issue"),
+            created_at: comment.get("created_at"),
+            user_login: comment.get("user_login"),
+            author_association: comment.get("author_association"),
+            is_toxic: comment.get("is_toxic"),
+            is_minimized: comment.get("is_minimized"),
+            minimized_reason: comment.get("minimized_reason"),
+            text: comment.get("text"),
+        };
+        writer
+            .serialize(row)
+            .expect("failed to write comment to csv");
+    }
+
+    writer.flush().unwrap();
+}
+
+/// Comments whose `updated_at` is later than `created_at` have been edited,
+/// the others have no history to fetch.
+async fn populate_revisions(conn: &mut SqliteConnection, client: &Github) {
+    let comments: Vec<(i64, String, String)> = sqlx::query(
+        r#"
+        SELECT id_comment, kind, node_id
+          FROM Comments
+         WHERE node_id IS NOT NULL AND updated_at > created_at
+         ORDER BY id_comment, kind
+        "#,
+    )
+    .fetch_all(&mut *conn)
+    .await
+    .unwrap()
+    .iter()
+    .map(|comment| {
+        (
+            comment.get("id_comment"),
+            comment.get("kind"),
+            comment.get("node_id"),
+        )
+    })
+    .collect();
+
+    let revisions = graphql::fetch_revisions(client, &comments).await;
+    store_revisions(conn, revisions).await;
+}
 
-            comments.extend(formated_comments);
-            page += 1;
+/// Take a snapshot of the metadata of every stored repository. Snapshots are
+/// kept per `fetched_at`, so running this again adds new ones.
+async fn snapshot_repositories(conn: &mut SqliteConnection, client: &Github) {
+    let repositories: Vec<(i64, String)> =
+        sqlx::query("SELECT id_repo, commits_url FROM Repositories")
+            .fetch_all(&mut *conn)
+            .await
+            .unwrap()
+            .iter()
+            .filter_map(|repository| {
+                let commits_url: String = repository.get("commits_url");
+                let url = commits_url.strip_suffix("/commits{/sha}")?.to_string();
+                Some((repository.get("id_repo"), url))
+            })
+            .collect();
+
+    let mut snapshots = stream::iter(repositories)
+        .map(|(id_repo, url)| async move {
+            println!("Retrieving Repository: {}", url);
+            let metadata = client.get_json::<RepositoryMetadata>(&url).await.ok();
+            (id_repo, metadata)
+        })
+        .buffer_unordered(client.max_parallelism());
+
+    let fetched_at = Utc::now().format("%FT%TZ").to_string();
+    while let Some((id_repo, metadata)) = snapshots.next().await {
+        if let Some(metadata) = metadata {
+            store_snapshot(conn, id_repo, metadata, &fetched_at).await;
         }
     }
+}
+
+/// `.../issues/NUMBER/comments` -> `.../pulls/NUMBER/comments`
+fn review_comments_url(comments_url: &str) -> Option<String> {
+    let (repository_url, path) = comments_url.rsplit_once("/issues/")?;
+    Some(format!("{}/pulls/{}", repository_url, path))
+}
+
+async fn fetch_comments(client: &Github, issues: &[(i64, String)]) -> HashSet<Comment> {
+    stream::iter(issues)
+        .map(|(id_issue, comments_url)| async move {
+            let url = &format!("{}?per_page=100", comments_url);
+            println!("Retrieving Comments: {}", url);
 
-    store_comments(conn, comments).await;
+            client
+                .paginate::<Vec<Comment>>(url)
+                .flat_map(stream::iter)
+                .map(|mut comment| {
+                    comment.issue_id = Some(*id_issue);
+                    comment
+                })
+                .collect::<Vec<_>>()
+                .await
+        })
+        .buffer_unordered(client.max_parallelism())
+        .flat_map(stream::iter)
+        .collect()
+        .await
 }
 
-async fn count_commits_and_forks(conn: &mut SqliteConnection, client: &Client) {
+async fn count_commits_and_forks(conn: &mut SqliteConnection, client: &Github) {
     let mut writer = csv::Writer::from_path("data.csv").unwrap();
 
     let comments = sqlx::query(
         r#"
-        SELECT id_comment, Issues.id_issue as id_issue, Comments.created_at as created_at, Repositories.commits_url as commits_url
+        SELECT id_comment, Issues.id_issue as id_issue, Comments.created_at as created_at, Repositories.id_repo as id_repo,
+               Repositories.commits_url as commits_url, Repositories.stars_url as stars_url, Repositories.forks_url as forks_url
           FROM Comments, Repositories, Issues 
          WHERE is_toxic = 1 and Comments.id_issue = Issues.id_issue and Issues.id_repo = Repositories.id_repo
         "#)
@@ -211,108 +647,292 @@ async fn count_commits_and_forks(conn: &mut SqliteConnection, client: &Client) {
         .await
         .unwrap();
 
-    for comment in comments.iter() {
-        let created_at: String = comment.get("created_at");
-        let commits_url = comment
-            .get::<String, _>("commits_url")
-            .strip_suffix("{/sha}")
-            .unwrap()
-            .to_string();
-        let id_issue: i32 = comment.get("id_issue");
+    let mut commits = stream::iter(comments.iter())
+        .map(|comment| async move {
+            let created_at: String = comment.get("created_at");
+            let commits_url = comment
+                .get::<String, _>("commits_url")
+                .strip_suffix("{/sha}")
+                .unwrap()
+                .to_string();
+            let id_issue: i64 = comment.get("id_issue");
+
+            let (since, until) = get_since_and_until(&created_at);
+
+            let before = fetch_commits(client, &commits_url, &since, &created_at).await;
+            let after = fetch_commits(client, &commits_url, &created_at, &until).await;
+
+            let before = before.into_iter().map(|commit| (commit, "before"));
+            let after = after.into_iter().map(|commit| (commit, "after"));
+
+            before
+                .chain(after)
+                .map(|(commit, before_or_after)| CommitFlat {
+                    id_issue,
+                    authored_date: commit.commit.author.date,
+                    committed_date: commit.commit.committer.date,
+                    before_or_after: before_or_after.to_string(),
+                })
+                .collect::<Vec<_>>()
+        })
+        .buffer_unordered(client.max_parallelism());
+
+    while let Some(rows) = commits.next().await {
+        for row in rows {
+            writer
+                .serialize(row)
+                .expect("failed to write commit to csv");
+        }
+
+        writer.flush().unwrap();
+    }
+
+    count_stars_and_forks(client, &comments).await;
+}
+
+/// Stars and forks in the same windows as the commits, in
+/// stars_and_forks.csv. Neither listing can be filtered by date, so each
+/// repository's is fetched once and shared by all its toxic comments.
+async fn count_stars_and_forks(client: &Github, comments: &[SqliteRow]) {
+    let mut writer = csv::Writer::from_path("stars_and_forks.csv").unwrap();
+
+    let repositories: HashMap<i64, (String, String)> = comments
+        .iter()
+        .map(|comment| {
+            (
+                comment.get("id_repo"),
+                (comment.get("stars_url"), comment.get("forks_url")),
+            )
+        })
+        .collect();
+
+    let events: HashMap<i64, Vec<(&str, String)>> = stream::iter(repositories)
+        .map(|(id_repo, (stars_url, forks_url))| async move {
+            let stars_url = format!("{}?per_page=100", stars_url);
+            println!("Retrieving Stargazers: {}", stars_url);
+            let stars: Vec<_> = client
+                .paginate_with_accept::<Vec<Stargazer>>(&stars_url, Some(STAR_MEDIA_TYPE))
+                .flat_map(stream::iter)
+                .map(|stargazer| ("star", stargazer.starred_at))
+                .collect()
+                .await;
+            if stars.len() >= MAX_STARGAZERS {
+                client.record_truncation(stars_url, stars.len().div_ceil(100));
+            }
+
+            let forks_url = format!("{}?per_page=100&sort=oldest", forks_url);
+            println!("Retrieving Forks: {}", forks_url);
+            let forks = client
+                .paginate::<Vec<Fork>>(&forks_url)
+                .flat_map(stream::iter)
+                .map(|fork| ("fork", fork.created_at));
+
+            (
+                id_repo,
+                stream::iter(stars).chain(forks).collect::<Vec<_>>().await,
+            )
+        })
+        .buffer_unordered(client.max_parallelism())
+        .collect()
+        .await;
 
+    for comment in comments {
+        let id_issue: i64 = comment.get("id_issue");
+        let id_repo: i64 = comment.get("id_repo");
+        let created_at: String = comment.get("created_at");
         let (since, until) = get_since_and_until(&created_at);
 
-        for page in 1..100 {
-            let url = &format!(
-                "{}?page={}&per_page=100&since={}&until={}",
-                commits_url, page, since, created_at
-            );
-            println!("Retrieving Commits: {}", url);
-
-            let response = {
-                match client.get_github(url).send().await {
-                    Ok(response) => response,
-                    _ => continue,
-                }
+        for (kind, date) in events.get(&id_repo).into_iter().flatten() {
+            let Some(before_or_after) = before_or_after(date, &since, &created_at, &until) else {
+                continue;
             };
 
-            let payload: Vec<Commit> = {
-                match response.json().await {
-                    Ok(commits) => commits,
-                    Err(_) => continue,
-                }
-            };
+            writer
+                .serialize(StarOrForkFlat {
+                    kind: kind.to_string(),
+                    date: date.clone(),
+                    before_or_after: before_or_after.to_string(),
+                    id_issue,
+                })
+                .expect("failed to write star or fork to csv");
+        }
+    }
 
-            if payload.is_empty() {
-                break;
-            }
+    writer.flush().unwrap();
+}
 
-            for commit in payload {
-                writer
-                    .serialize(CommitFlat {
-                        id_issue,
-                        authored_date: commit.commit.author.date,
-                        committed_date: commit.commit.committer.date,
-                        before_or_after: "before".to_string(),
-                    })
-                    .expect("failed to write commit to csv");
-            }
-        }
+async fn fetch_commits(
+    client: &Github,
+    commits_url: &str,
+    since: &str,
+    until: &str,
+) -> Vec<Commit> {
+    let url = &format!(
+        "{}?per_page=100&since={}&until={}",
+        commits_url, since, until
+    );
+    println!("Retrieving Commits: {}", url);
+
+    client
+        .paginate::<Vec<Commit>>(url)
+        .flat_map(stream::iter)
+        .collect()
+        .await
+}
 
-        for page in 1..100 {
-            let url = &format!(
-                "{}?page={}&per_page=100&since={}&until={}",
-                commits_url, page, created_at, until
-            );
-            println!("Retrieving Commits: {}", url);
-
-            let response = {
-                match client.get_github(url).send().await {
-                    Ok(response) => response,
-                    _ => continue,
-                }
-            };
+/// Upper bound for the search for the highest repository id, far beyond
+/// anything GitHub will reach.
+const MAX_REPO_ID_PROBE: i64 = 1 << 40;
 
-            let payload: Vec<Commit> = {
-                match response.json().await {
-                    Ok(list) => list,
-                    Err(_) => continue,
-                }
-            };
+/// Ranges of repository ids that have already been sampled, as `since` ->
+/// highest id returned for it. Ranges may overlap.
+type SeenIds = BTreeMap<i64, i64>;
 
-            if payload.is_empty() {
-                break;
-            }
+/// How many random ids are drawn in search of one that hasn't been sampled
+/// yet before concluding that (nearly) every id has been.
+const MAX_RANDOM_DRAWS: usize = 1000;
 
-            for commit in payload {
-                writer
-                    .serialize(CommitFlat {
-                        id_issue,
-                        authored_date: commit.commit.author.date,
-                        committed_date: commit.commit.committer.date,
-                        before_or_after: "after".to_string(),
-                    })
-                    .expect("failed to write commit to csv");
-            }
-        }
+fn get_random_repo_id(rng: &mut impl Rng, seen_ids: &SeenIds, max_id: i64) -> Option<i64> {
+    (0..MAX_RANDOM_DRAWS)
+        .map(|_| rng.gen_range(0..max_id))
+        .find(|&id| !seen_ids.range(..=id).any(|(_, &until)| id <= until))
+}
 
-        writer.flush().unwrap();
-    }
+fn get_repo_url(client: &Github, since: i64) -> String {
+    format!("{}?since={}", client.url("/repositories"), since)
 }
 
-type SeenIds = HashSet<u16>;
+/// The highest repository id on GitHub, found by binary search on the
+/// `since` parameter of `/repositories`, which lists nothing past it.
+async fn discover_max_repo_id(client: &Github) -> Result<i64, String> {
+    let has_repositories_after = |since: i64| async move {
+        let url = get_repo_url(client, since);
+        let repositories: Vec<Repository> = client.get_json(&url).await.map_err(|err| {
+            format!(
+                "failed to discover the highest repository id, pass --max-repo-id instead: {}",
+                err
+            )
+        })?;
+        Ok::<_, String>(!repositories.is_empty())
+    };
 
-fn get_random_repo_url(seen_ids: &mut SeenIds) -> String {
-    let random_id = {
-        loop {
-            let id = rand::random::<u16>();
-            if !seen_ids.contains(&id) {
-                break id;
-            }
+    let mut low = 0;
+    let mut high = 1 << 20;
+    while high < MAX_REPO_ID_PROBE && has_repositories_after(high).await? {
+        low = high;
+        high *= 2;
+    }
+
+    while high - low > 1 {
+        let middle = low + (high - low) / 2;
+        if has_repositories_after(middle).await? {
+            low = middle;
+        } else {
+            high = middle;
         }
-    };
-    seen_ids.insert(random_id);
-    format!("{}?since={}", GITUHB_REPO_URL, random_id)
+    }
+
+    Ok(high)
+}
+
+async fn load_sampled_ranges(conn: &mut SqliteConnection) -> SeenIds {
+    sqlx::query("SELECT since, until FROM SampledRanges")
+        .fetch_all(&mut *conn)
+        .await
+        .unwrap()
+        .iter()
+        .map(|range| (range.get("since"), range.get("until")))
+        .collect()
+}
+
+async fn store_sampled_range(conn: &mut SqliteConnection, since: i64, until: i64) {
+    let sampled_at = Utc::now().format("%FT%TZ").to_string();
+
+    sqlx::query!(
+        r#"
+        INSERT OR REPLACE INTO SampledRanges (since, until, sampled_at)
+        VALUES ($1, $2, $3)
+        "#,
+        since,
+        until,
+        sampled_at
+    )
+    .execute(&mut *conn)
+    .await
+    .expect("failed to store sampled range in database");
+}
+
+async fn load_strata_counts(conn: &mut SqliteConnection) -> HashMap<Stratum, usize> {
+    sqlx::query(
+        "SELECT language, stars_bucket, created_year, COUNT(*) AS count \
+         FROM RepositoryStrata GROUP BY language, stars_bucket, created_year",
+    )
+    .fetch_all(&mut *conn)
+    .await
+    .unwrap()
+    .iter()
+    .map(|row| {
+        let stratum = Stratum {
+            language: row.get("language"),
+            stars: row.get("stars_bucket"),
+            created_year: row.get("created_year"),
+        };
+        let count: i64 = row.get("count");
+        (stratum, count as usize)
+    })
+    .collect()
+}
+
+async fn store_snapshot(
+    conn: &mut SqliteConnection,
+    id_repo: i64,
+    metadata: RepositoryMetadata,
+    fetched_at: &str,
+) {
+    let topics = serde_json::to_string(&metadata.topics).unwrap();
+    let license = metadata.license.and_then(|license| license.spdx_id);
+
+    sqlx::query!(
+        r#"
+        INSERT OR REPLACE INTO RepositorySnapshots (id_repo, fetched_at, stargazers_count, forks_count, subscribers_count, open_issues_count, language, topics, license, archived, owner_type, created_at, pushed_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)
+        "#,
+        id_repo,
+        fetched_at,
+        metadata.stargazers_count,
+        metadata.forks_count,
+        metadata.subscribers_count,
+        metadata.open_issues_count,
+        metadata.language,
+        topics,
+        license,
+        metadata.archived,
+        metadata.owner.kind,
+        metadata.created_at,
+        metadata.pushed_at
+    )
+    .execute(&mut *conn)
+    .await
+    .expect("failed to store repository snapshot in database");
+}
+
+async fn store_stratum(conn: &mut SqliteConnection, id_repo: i64, stratum: Stratum) {
+    let sampled_at = Utc::now().format("%FT%TZ").to_string();
+
+    sqlx::query!(
+        r#"
+        INSERT OR REPLACE INTO RepositoryStrata (id_repo, language, stars_bucket, created_year, sampled_at)
+        VALUES ($1, $2, $3, $4, $5)
+        "#,
+        id_repo,
+        stratum.language,
+        stratum.stars,
+        stratum.created_year,
+        sampled_at
+    )
+    .execute(&mut *conn)
+    .await
+    .expect("failed to store repository stratum in database");
 }
 
 async fn store_respository(conn: &mut SqliteConnection, repository: Repository) {
@@ -334,16 +954,34 @@ async fn store_respository(conn: &mut SqliteConnection, repository: Repository)
 
 async fn store_issues(conn: &mut SqliteConnection, issues: HashSet<Issue>) {
     for issue in issues {
+        let is_pull_request = issue.is_pull_request();
+        let user_login = issue.user.as_ref().map(|user| &user.login);
+        let user_id = issue.user.as_ref().map(|user| user.id);
+        let labels: Vec<&str> = issue
+            .labels
+            .iter()
+            .map(|label| label.name.as_str())
+            .collect();
+        let labels = serde_json::to_string(&labels).unwrap();
+
         sqlx::query!(
             r#"
-        INSERT OR IGNORE INTO Issues (id_issue, id_repo, created_at, title, comments_url)
-        VALUES ($1, $2, $3, $4, $5)
+        INSERT OR IGNORE INTO Issues (id_issue, id_repo, created_at, title, comments_url, lock_reason, state, is_pull_request, number, body, user_login, user_id, labels)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)
         "#,
             issue.id,
             issue.repository_id,
             issue.created_at,
             issue.title,
-            issue.comments_url
+            issue.comments_url,
+            issue.active_lock_reason,
+            issue.state,
+            is_pull_request,
+            issue.number,
+            issue.body,
+            user_login,
+            user_id,
+            labels
         )
         .execute(&mut *conn)
         .await
@@ -351,25 +989,238 @@ async fn store_issues(conn: &mut SqliteConnection, issues: HashSet<Issue>) {
     }
 }
 
-async fn store_comments(conn: &mut SqliteConnection, comments: HashSet<Comment>) {
+/// `kind` tells apart issue comments from pull request review comments,
+/// whose ids may collide. Comments stored before are given the author, edit
+/// date and node id just fetched, but keep their text and toxicity label.
+async fn store_comments(conn: &mut SqliteConnection, comments: HashSet<Comment>, kind: &str) {
     for comment in comments {
+        let user_login = comment.user.as_ref().map(|user| &user.login);
+        let user_id = comment.user.as_ref().map(|user| user.id);
+
         sqlx::query!(
             r#"
-        INSERT OR IGNORE INTO Comments (id_comment, id_issue, created_at, text, is_toxic)
-        VALUES ($1, $2, $3, $4, $5)
+        INSERT INTO Comments (id_comment, kind, id_issue, created_at, text, is_toxic, user_login, user_id, author_association, updated_at, node_id)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+            ON CONFLICT (id_comment, kind) DO UPDATE
+           SET user_login = excluded.user_login, user_id = excluded.user_id,
+               author_association = excluded.author_association,
+               updated_at = excluded.updated_at, node_id = excluded.node_id
         "#,
             comment.id,
+            kind,
             comment.issue_id,
             comment.created_at,
             comment.body,
-            0
+            0,
+            user_login,
+            user_id,
+            comment.author_association,
+            comment.updated_at,
+            comment.node_id
         )
         .execute(&mut *conn)
         .await
         .expect("failed to store comment in database");
+
+        // Reactions keep coming in after a comment is posted, so unlike the
+        // comment itself they are replaced with the latest counts.
+        if let Some(reactions) = comment.reactions {
+            sqlx::query!(
+                r#"
+        INSERT OR REPLACE INTO CommentReactions (id_comment, kind, total_count, plus_one, minus_one, laugh, hooray, confused, heart, rocket, eyes)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+        "#,
+                comment.id,
+                kind,
+                reactions.total_count,
+                reactions.plus_one,
+                reactions.minus_one,
+                reactions.laugh,
+                reactions.hooray,
+                reactions.confused,
+                reactions.heart,
+                reactions.rocket,
+                reactions.eyes
+            )
+            .execute(&mut *conn)
+            .await
+            .expect("failed to store comment reactions in database");
+        }
+    }
+}
+
+async fn store_minimizations(conn: &mut SqliteConnection, minimizations: Vec<Minimization>) {
+    for minimization in minimizations {
+        sqlx::query!(
+            r#"
+        UPDATE Comments
+           SET is_minimized = $1, minimized_reason = $2
+         WHERE id_comment = $3 AND kind = $4
+        "#,
+            minimization.is_minimized,
+            minimization.reason,
+            minimization.id_comment,
+            minimization.kind
+        )
+        .execute(&mut *conn)
+        .await
+        .expect("failed to store comment minimization in database");
+    }
+}
+
+async fn store_revisions(conn: &mut SqliteConnection, revisions: Vec<CommentRevision>) {
+    for revision in revisions {
+        sqlx::query!(
+            r#"
+        INSERT OR IGNORE INTO CommentRevisions (id_revision, id_comment, kind, edited_at, deleted_at, editor, text)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        "#,
+            revision.id,
+            revision.id_comment,
+            revision.kind,
+            revision.edited_at,
+            revision.deleted_at,
+            revision.editor,
+            revision.body
+        )
+        .execute(&mut *conn)
+        .await
+        .expect("failed to store comment revision in database");
+    }
+}
+
+/// Copy the body of every issue into `Comments`, under the issue's own id, so
+/// that labeling and exports see the whole conversation. Opening posts stored
+/// before their author was recorded get it filled in.
+async fn store_opening_posts(conn: &mut SqliteConnection) {
+    sqlx::query!(
+        r#"
+        INSERT INTO Comments (id_comment, kind, id_issue, created_at, text, is_toxic, user_login, user_id)
+        SELECT id_issue, 'opening_post', id_issue, created_at, body, 0, user_login, user_id
+          FROM Issues
+         WHERE body IS NOT NULL
+            ON CONFLICT (id_comment, kind) DO UPDATE
+           SET user_login = excluded.user_login, user_id = excluded.user_id
+        "#
+    )
+    .execute(&mut *conn)
+    .await
+    .expect("failed to store opening posts in database");
+}
+
+/// Store when, by whom and why each issue was locked and unlocked.
+async fn store_lock_events(conn: &mut SqliteConnection, timelines: &[(i64, Vec<TimelineEvent>)]) {
+    for (id_issue, events) in timelines {
+        for event in events {
+            if event.event != "locked" && event.event != "unlocked" {
+                continue;
+            }
+            let actor = event.actor.as_ref().map(|actor| &actor.login);
+
+            sqlx::query!(
+                r#"
+        INSERT OR IGNORE INTO LockEvents (id_event, id_issue, event, actor, created_at, lock_reason)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        "#,
+                event.id,
+                id_issue,
+                event.event,
+                actor,
+                event.created_at,
+                event.lock_reason
+            )
+            .execute(&mut *conn)
+            .await
+            .expect("failed to store lock event in database");
+        }
     }
 }
 
+/// Replace the stored timeline of each issue with the one just fetched.
+/// Locked issues always have some events, so an empty timeline means it
+/// couldn't be fetched and the stored one is kept.
+async fn store_timeline_events(
+    conn: &mut SqliteConnection,
+    timelines: &[(i64, Vec<TimelineEvent>)],
+) {
+    for (id_issue, events) in timelines {
+        if events.is_empty() {
+            continue;
+        }
+
+        sqlx::query!("DELETE FROM TimelineEvents WHERE id_issue = $1", id_issue)
+            .execute(&mut *conn)
+            .await
+            .expect("failed to clear timeline events in database");
+
+        for (position, event) in events.iter().enumerate() {
+            let position = position as i64;
+            let actor = event.actor.as_ref().map(|actor| &actor.login);
+            let detail = event.detail();
+
+            sqlx::query!(
+                r#"
+        INSERT INTO TimelineEvents (id_issue, position, id_event, event, actor, created_at, detail)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        "#,
+                id_issue,
+                position,
+                event.id,
+                event.event,
+                actor,
+                event.created_at,
+                detail
+            )
+            .execute(&mut *conn)
+            .await
+            .expect("failed to store timeline event in database");
+        }
+    }
+}
+
+/// Store the requests the client gave up on and the listings it cut short,
+/// so we know which parts of the dataset are incomplete.
+async fn store_client_reports(conn: &mut SqliteConnection, client: &Github) {
+    let reported_at = Utc::now().format("%FT%TZ").to_string();
+
+    for failure in client.take_failures() {
+        sqlx::query!(
+            r#"
+        INSERT OR REPLACE INTO FailedRequests (url, status, error, failed_at)
+        VALUES ($1, $2, $3, $4)
+        "#,
+            failure.url,
+            failure.status,
+            failure.error,
+            reported_at
+        )
+        .execute(&mut *conn)
+        .await
+        .expect("failed to store failed request in database");
+    }
+
+    for truncation in client.take_truncations() {
+        let pages = truncation.pages as i64;
+        sqlx::query!(
+            r#"
+        INSERT OR REPLACE INTO TruncatedRequests (url, pages, truncated_at)
+        VALUES ($1, $2, $3)
+        "#,
+            truncation.url,
+            pages,
+            reported_at
+        )
+        .execute(&mut *conn)
+        .await
+        .expect("failed to store truncated request in database");
+    }
+}
+
+fn parse_date(date: &str) -> Result<DateTime<Utc>, chrono::ParseError> {
+    let date = NaiveDate::parse_from_str(date, "%F")?;
+    Ok(date.and_hms_opt(0, 0, 0).unwrap().and_utc())
+}
+
 fn get_since_and_until(input_date: &str) -> (String, String) {
     let parsed_date = NaiveDateTime::parse_from_str(input_date, "%FT%TZ").unwrap();
 
@@ -382,41 +1233,309 @@ fn get_since_and_until(input_date: &str) -> (String, String) {
     (since, until)
 }
 
+/// Whether `date` falls in the window from `since` to `pivot`, or from
+/// `pivot` to `until`. Dates are all formatted the same way, so they compare
+/// as strings.
+fn before_or_after(date: &str, since: &str, pivot: &str, until: &str) -> Option<&'static str> {
+    if since <= date && date < pivot {
+        Some("before")
+    } else if pivot <= date && date <= until {
+        Some("after")
+    } else {
+        None
+    }
+}
+
+fn token_pool(opts: &Opts) -> Result<TokenPool, String> {
+    if opts.unauthenticated
+        || opts.replay.is_some()
+        || opts.offline
+        || opts.export_comments
+        || !opts.gharchive.is_empty()
+    {
+        return Ok(TokenPool::unauthenticated());
+    }
+
+    let mut tokens = TokenPool::personal_tokens(opts.tokens_file.as_deref())?;
+
+    if let (Some(app_id), Some(app_key)) = (&opts.app_id, &opts.app_key) {
+        let app = Arc::new(App::new(app_id, app_key)?);
+        tokens.extend(
+            opts.installation_id
+                .iter()
+                .map(|&installation_id| Token::installation(app.clone(), installation_id)),
+        );
+    }
+
+    TokenPool::new(tokens)
+}
+
 #[tokio::main]
 async fn main() {
     let opts = Opts::from_args();
 
-    let mut seen_ids = HashSet::new();
+    let mut rng = match opts.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
 
-    let client = Client::new();
-    let mut url = get_random_repo_url(&mut seen_ids);
+    let cache = opts
+        .cache_dir
+        .as_ref()
+        .map(|dir| Cache::new(dir).offline(opts.offline));
+    let fixtures = match (&opts.record, &opts.replay) {
+        (Some(dir), _) => Some(Fixtures::new(dir, Mode::Record)),
+        (_, Some(dir)) => Some(Fixtures::new(dir, Mode::Replay)),
+        _ => None,
+    };
+    let tokens = token_pool(&opts).unwrap_or_else(|err| {
+        eprintln!("error: {}", err);
+        std::process::exit(1);
+    });
+    let client = Github::new(Client::new(), tokens)
+        .api_url(&opts.api_url)
+        .graphql_url(opts.graphql_url.as_deref())
+        .cache(cache)
+        .fixtures(fixtures)
+        .max_attempts(opts.max_attempts)
+        .max_pages(opts.max_pages)
+        .parallelism(opts.parallelism);
+
+    let filter = IssueFilter {
+        lock_reasons: opts.lock_reasons.clone(),
+        states: opts.states.clone(),
+    };
 
     let mut conn = SqliteConnection::connect(&opts.database_url).await.unwrap();
+    schema::migrate(&mut conn).await;
 
     if opts.populate_comments {
         println!("Retrieving and storing Comments for all Issues...");
-        populate_comments(&mut conn, &client).await;
+        populate_comments(&mut conn, &client, opts.backend, opts.opening_posts).await;
+        store_client_reports(&mut conn, &client).await;
+    } else if opts.populate_revisions {
+        println!("Retrieving and storing the edit history of all Comments...");
+        populate_revisions(&mut conn, &client).await;
+        store_client_reports(&mut conn, &client).await;
+    } else if opts.snapshot_repositories {
+        println!("Taking a snapshot of all Repositories...");
+        snapshot_repositories(&mut conn, &client).await;
+        store_client_reports(&mut conn, &client).await;
+    } else if opts.churn {
+        println!("Comparing contributors before and after each Issue was locked...");
+        churn::contributor_churn(&mut conn, &client).await;
+        store_client_reports(&mut conn, &client).await;
+    } else if opts.export_comments {
+        println!("Exporting Comments to comments.csv...");
+        export_comments(&mut conn).await;
     } else if opts.generate_csv {
         println!("Counting commits, forks and generating CSV...");
         count_commits_and_forks(&mut conn, &client).await;
+        store_client_reports(&mut conn, &client).await;
+    } else if !opts.gharchive.is_empty() {
+        println!("Ingesting GH Archive dumps...");
+        gharchive::ingest(&mut conn, &opts.gharchive, &filter).await;
+    } else if opts.discover {
+        println!("Discovering locked issues through the search API...");
+        let until = opts.discover_until.unwrap_or_else(Utc::now);
+        search::discover_issues(&mut conn, &client, &filter, opts.discover_since, until).await;
+        store_client_reports(&mut conn, &client).await;
     } else {
+        let max_id = match opts.max_repo_id {
+            Some(max_id) => max_id,
+            None => discover_max_repo_id(&client).await.unwrap_or_else(|err| {
+                eprintln!("error: {}", err);
+                std::process::exit(1);
+            }),
+        };
+        let mut seen_ids = load_sampled_ranges(&mut conn).await;
+        println!(
+            "Sampling repository ids up to {} ({} ranges already sampled)",
+            max_id,
+            seen_ids.len()
+        );
+
+        let mut strata = Strata::new(
+            opts.languages.clone(),
+            opts.star_buckets.clone(),
+            opts.creation_years.clone(),
+            opts.quota,
+        )
+        .counts(load_strata_counts(&mut conn).await);
+
         for _ in 0..opts.iterations.unwrap() {
+            if strata.is_full() {
+                println!("Every stratum has reached its quota");
+                break;
+            }
+
+            let Some(since) = get_random_repo_id(&mut rng, &seen_ids, max_id) else {
+                println!(
+                    "No unsampled repository id found in {} draws, stopping",
+                    MAX_RANDOM_DRAWS
+                );
+                break;
+            };
+            let url = get_repo_url(&client, since);
+
             println!("Searching repositories: {}", url);
             let repositories = get_repositories(&client, &url).await;
+            let until = repositories.iter().map(|repository| repository.id).max();
+
+            let repositories = if strata.is_enabled() {
+                let sampled = strata::sample(&client, &mut strata, repositories).await;
+                let mut repositories = Vec::new();
+                for (repository, stratum) in sampled {
+                    store_stratum(&mut conn, repository.id, stratum).await;
+                    repositories.push(repository);
+                }
+                repositories
+            } else {
+                repositories
+            };
 
-            for repository in repositories {
-                println!("Searching issues: {}", repository.name);
-
-                let too_heated_issues = search_too_heated_issues(&client, &repository).await;
-                if !too_heated_issues.is_empty() {
-                    println!("Found too heated issues in repository: {}", repository.name);
+            let mut searches = stream::iter(repositories)
+                .map(|repository| {
+                    let client = &client;
+                    let filter = &filter;
+                    async move {
+                        println!("Searching issues: {}", repository.name);
+                        let locked_issues = match opts.backend {
+                            Backend::Rest => {
+                                search_locked_issues(client, &repository, filter).await
+                            }
+                            Backend::Graphql => {
+                                graphql::search_locked_issues(client, &repository, filter).await
+                            }
+                        };
+                        (repository, locked_issues)
+                    }
+                })
+                .buffer_unordered(client.max_parallelism());
+
+            while let Some((repository, locked_issues)) = searches.next().await {
+                if !locked_issues.is_empty() {
+                    println!("Found locked issues in repository: {}", repository.name);
                     store_respository(&mut conn, repository).await;
-                    store_issues(&mut conn, too_heated_issues).await;
+                    store_issues(&mut conn, locked_issues).await;
                 }
             }
 
-            url = get_random_repo_url(&mut seen_ids);
-            std::thread::sleep(Duration::from_secs(5));
+            if let Some(until) = until {
+                store_sampled_range(&mut conn, since, until).await;
+                seen_ids.insert(since, until);
+            }
+
+            store_client_reports(&mut conn, &client).await;
+
+            if let Some(budget) = client.budget("core") {
+                println!("Remaining requests: {}/{}", budget.remaining, budget.limit);
+            }
         }
     }
+
+    if client.graphql_cost() > 0 {
+        println!("GraphQL points spent: {}", client.graphql_cost());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn comment(user: Option<User>, node_id: Option<&str>) -> Comment {
+        Comment {
+            id: 11,
+            body: "c1".to_string(),
+            created_at: "2023-03-01T11:00:00Z".to_string(),
+            issue_id: None,
+            user,
+            author_association: node_id.map(|_| "NONE".to_string()),
+            updated_at: node_id.map(|_| "2023-03-01T11:30:00Z".to_string()),
+            reactions: None,
+            node_id: node_id.map(String::from),
+        }
+    }
+
+    #[tokio::test]
+    async fn store_comments_fills_in_metadata_of_stored_comments() {
+        let mut conn = SqliteConnection::connect("sqlite::memory:").await.unwrap();
+        schema::migrate(&mut conn).await;
+
+        store_comments(
+            &mut conn,
+            HashSet::from([comment(None, None)]),
+            "issue_comment",
+        )
+        .await;
+        sqlx::query("UPDATE Comments SET is_toxic = 1, text = 'labeled'")
+            .execute(&mut conn)
+            .await
+            .unwrap();
+
+        let user = User {
+            login: "bob".to_string(),
+            id: 2,
+        };
+        let fetched = comment(Some(user), Some("IC_1"));
+        store_comments(&mut conn, HashSet::from([fetched]), "issue_comment").await;
+
+        let stored = sqlx::query("SELECT * FROM Comments")
+            .fetch_one(&mut conn)
+            .await
+            .unwrap();
+        assert_eq!(stored.get::<String, _>("user_login"), "bob");
+        assert_eq!(stored.get::<i64, _>("user_id"), 2);
+        assert_eq!(stored.get::<String, _>("author_association"), "NONE");
+        assert_eq!(
+            stored.get::<String, _>("updated_at"),
+            "2023-03-01T11:30:00Z"
+        );
+        assert_eq!(stored.get::<String, _>("node_id"), "IC_1");
+        assert_eq!(stored.get::<String, _>("text"), "labeled");
+        assert!(stored.get::<bool, _>("is_toxic"));
+    }
+
+    #[test]
+    fn before_or_after_splits_the_window_at_the_pivot() {
+        let (since, until) = get_since_and_until("2023-03-01T10:00:00Z");
+        let pivot = "2023-03-01T10:00:00Z";
+
+        assert_eq!(
+            before_or_after("2023-01-30T10:00:00Z", &since, pivot, &until),
+            Some("before")
+        );
+        assert_eq!(
+            before_or_after("2023-01-30T09:59:59Z", &since, pivot, &until),
+            None
+        );
+        assert_eq!(before_or_after(pivot, &since, pivot, &until), Some("after"));
+        assert_eq!(
+            before_or_after("2023-03-31T10:00:00Z", &since, pivot, &until),
+            Some("after")
+        );
+        assert_eq!(
+            before_or_after("2023-03-31T10:00:01Z", &since, pivot, &until),
+            None
+        );
+    }
+
+    #[test]
+    fn random_repo_id_skips_ids_in_overlapping_ranges() {
+        // The second range starts inside the first and ends before it does.
+        let seen_ids = SeenIds::from([(0, 99), (50, 60)]);
+
+        for seed in 0..10 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            assert_eq!(get_random_repo_id(&mut rng, &seen_ids, 101), Some(100));
+        }
+    }
+
+    #[test]
+    fn random_repo_id_gives_up_once_every_id_was_sampled() {
+        let seen_ids = SeenIds::from([(0, 60), (50, 100)]);
+        let mut rng = StdRng::seed_from_u64(0);
+
+        assert_eq!(get_random_repo_id(&mut rng, &seen_ids, 101), None);
+    }
 }
@@ -0,0 +1,292 @@
+use sqlx::{sqlite::SqliteConnection, Connection, Executor, Row};
+
+const SCHEMA: &str = include_str!("../db/schema.sql");
+
+/// Values for rows stored before a column was added, where something better
+/// than NULL is known: issues used to be collected only when closed and
+/// locked as too heated.
+const BACKFILLS: &[(&str, &str, &str)] = &[
+    ("Issues", "lock_reason", "'too heated'"),
+    ("Issues", "state", "'closed'"),
+];
+
+/// A column as reported by `PRAGMA table_info`.
+struct Column {
+    name: String,
+    kind: String,
+    not_null: bool,
+    default: Option<String>,
+    /// Position in the primary key, starting at 1, or 0 if not part of it.
+    primary_key: i64,
+}
+
+impl Column {
+    fn definition(&self) -> String {
+        let mut definition = format!("`{}` {}", self.name, self.kind);
+        if self.not_null {
+            definition.push_str(" NOT NULL");
+        }
+        if let Some(default) = &self.default {
+            definition.push_str(&format!(" DEFAULT {}", default));
+        }
+        definition
+    }
+}
+
+fn primary_key(columns: &[Column]) -> Vec<&str> {
+    let mut key: Vec<&Column> = columns.iter().filter(|c| c.primary_key > 0).collect();
+    key.sort_by_key(|column| column.primary_key);
+    key.into_iter().map(|column| column.name.as_str()).collect()
+}
+
+/// Bring the database up to date with `db/schema.sql`. Tables are created
+/// with `CREATE TABLE IF NOT EXISTS`, which leaves tables created by an older
+/// schema as they were, so the columns added since are added here. Tables
+/// whose primary key changed, which SQLite can't alter, are recreated and
+/// their rows copied over. Running it again changes nothing.
+pub async fn migrate(conn: &mut SqliteConnection) {
+    let mut expected = SqliteConnection::connect("sqlite::memory:").await.unwrap();
+    expected
+        .execute(SCHEMA)
+        .await
+        .expect("failed to create schema in memory");
+
+    // Dropping the old copy of a recreated table must neither check nor
+    // cascade to the rows referencing it, and renaming it must leave those
+    // references pointing at the new one.
+    let foreign_keys: bool = sqlx::query_scalar("PRAGMA foreign_keys")
+        .fetch_one(&mut *conn)
+        .await
+        .unwrap();
+    conn.execute("PRAGMA foreign_keys = OFF").await.unwrap();
+
+    let mut tx = conn.begin().await.unwrap();
+    tx.execute("PRAGMA legacy_alter_table = ON").await.unwrap();
+
+    let mut recreated = Vec::new();
+    for table in tables(&mut expected).await {
+        let existing = columns(&mut tx, &table).await;
+        if existing.is_empty() {
+            continue;
+        }
+        let wanted = columns(&mut expected, &table).await;
+        let missing = wanted
+            .iter()
+            .filter(|column| !existing.iter().any(|existing| existing.name == column.name));
+
+        if primary_key(&existing) != primary_key(&wanted) {
+            println!(
+                "Recreating table {} with primary key ({})",
+                table,
+                primary_key(&wanted).join(", ")
+            );
+            tx.execute(&*format!(
+                "ALTER TABLE `{}` RENAME TO `{}_old`",
+                table, table
+            ))
+            .await
+            .expect("failed to rename table in database");
+
+            let kept: Vec<String> = existing
+                .iter()
+                .filter(|column| wanted.iter().any(|wanted| wanted.name == column.name))
+                .map(|column| format!("`{}`", column.name))
+                .collect();
+            let missing: Vec<String> = missing.map(|column| column.name.clone()).collect();
+            recreated.push((table, kept.join(", "), missing));
+            continue;
+        }
+
+        for column in missing {
+            println!("Adding column {}.{}", table, column.name);
+            tx.execute(&*format!(
+                "ALTER TABLE `{}` ADD COLUMN {}",
+                table,
+                column.definition()
+            ))
+            .await
+            .expect("failed to add column in database");
+
+            backfill(&mut tx, &table, &column.name).await;
+        }
+    }
+
+    tx.execute(SCHEMA)
+        .await
+        .expect("failed to create tables in database");
+
+    for (table, kept, missing) in recreated {
+        tx.execute(&*format!(
+            "INSERT INTO `{}` ({}) SELECT {} FROM `{}_old`",
+            table, kept, kept, table
+        ))
+        .await
+        .expect("failed to copy rows in database");
+        tx.execute(&*format!("DROP TABLE `{}_old`", table))
+            .await
+            .expect("failed to drop table in database");
+
+        for column in missing {
+            backfill(&mut tx, &table, &column).await;
+        }
+    }
+
+    tx.execute("PRAGMA legacy_alter_table = OFF").await.unwrap();
+    tx.commit().await.unwrap();
+
+    if foreign_keys {
+        conn.execute("PRAGMA foreign_keys = ON").await.unwrap();
+    }
+}
+
+async fn backfill(conn: &mut SqliteConnection, table: &str, column: &str) {
+    let backfill = BACKFILLS
+        .iter()
+        .find(|&&(t, c, _)| t == table && c == column);
+    if let Some((_, _, value)) = backfill {
+        conn.execute(&*format!("UPDATE `{}` SET `{}` = {}", table, column, value))
+            .await
+            .expect("failed to backfill column in database");
+    }
+}
+
+async fn tables(conn: &mut SqliteConnection) -> Vec<String> {
+    sqlx::query("SELECT name FROM sqlite_master WHERE type = 'table' ORDER BY name")
+        .fetch_all(&mut *conn)
+        .await
+        .unwrap()
+        .iter()
+        .map(|table| table.get("name"))
+        .collect()
+}
+
+/// The columns of `table`, none if it doesn't exist.
+async fn columns(conn: &mut SqliteConnection, table: &str) -> Vec<Column> {
+    sqlx::query(&format!("PRAGMA table_info(`{}`)", table))
+        .fetch_all(&mut *conn)
+        .await
+        .unwrap()
+        .iter()
+        .map(|column| Column {
+            name: column.get("name"),
+            kind: column.get("type"),
+            not_null: column.get("notnull"),
+            default: column.get("dflt_value"),
+            primary_key: column.get("pk"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The tables of `db/schema.sql` before issues had a lock reason.
+    const BASELINE_SCHEMA: &str = r#"
+        CREATE TABLE `Repositories` (
+            `id_repo` INTEGER,
+            `name` text NOT NULL,
+            `stars_url` text NOT NULL,
+            `forks_url` text NOT NULL,
+            `commits_url` text NOT NULL,
+            PRIMARY KEY (`id_repo`)
+        );
+        CREATE TABLE `Issues` (
+            `id_issue` INTEGER,
+            `id_repo` INTEGER,
+            `created_at` text,
+            `title` text NOT NULL,
+            `comments_url` text NOT NULL,
+            PRIMARY KEY (`id_issue`),
+            FOREIGN KEY(`id_repo`) REFERENCES Repositories(`id_repo`)
+        );
+        CREATE TABLE `Comments` (
+            `id_comment` INTEGER,
+            `id_issue` INTEGER,
+            `created_at` text,
+            `text` TEXT NOT NULL,
+            `is_toxic` INTEGER(1) NOT NULL DEFAULT 0,
+            PRIMARY KEY (`id_comment`),
+            FOREIGN KEY(`id_issue`) REFERENCES Issues(`id_issue`)
+        );
+    "#;
+
+    #[tokio::test]
+    async fn migrate_adds_missing_columns_and_tables() {
+        let mut conn = SqliteConnection::connect("sqlite::memory:").await.unwrap();
+        conn.execute(BASELINE_SCHEMA).await.unwrap();
+        conn.execute(
+            "INSERT INTO Repositories (id_repo, name, stars_url, forks_url, commits_url) \
+             VALUES (5, 'r', 's', 'f', 'c'); \
+             INSERT INTO Issues (id_issue, id_repo, created_at, title, comments_url) \
+             VALUES (1, 5, '2023-03-01T10:00:00Z', 't1', 'u')",
+        )
+        .await
+        .unwrap();
+
+        migrate(&mut conn).await;
+        migrate(&mut conn).await;
+
+        let issue = sqlx::query("SELECT title, lock_reason, is_pull_request FROM Issues")
+            .fetch_one(&mut conn)
+            .await
+            .unwrap();
+        assert_eq!(issue.get::<String, _>("title"), "t1");
+        assert_eq!(issue.get::<String, _>("lock_reason"), "too heated");
+        assert!(!issue.get::<bool, _>("is_pull_request"));
+
+        let mut expected = SqliteConnection::connect("sqlite::memory:").await.unwrap();
+        expected.execute(SCHEMA).await.unwrap();
+        assert_eq!(tables(&mut conn).await, tables(&mut expected).await);
+    }
+
+    #[tokio::test]
+    async fn migrate_recreates_comments_keyed_by_kind() {
+        let mut conn = SqliteConnection::connect("sqlite::memory:").await.unwrap();
+        conn.execute(BASELINE_SCHEMA).await.unwrap();
+        // As if the new schema had been loaded over the old one by hand.
+        conn.execute(SCHEMA).await.unwrap();
+        conn.execute(
+            "INSERT INTO Comments (id_comment, id_issue, created_at, text, is_toxic) \
+             VALUES (11, NULL, '2023-03-01T11:00:00Z', 'c1', 1)",
+        )
+        .await
+        .unwrap();
+
+        migrate(&mut conn).await;
+        migrate(&mut conn).await;
+
+        let key: Vec<String> = sqlx::query("PRAGMA table_info(Comments)")
+            .fetch_all(&mut conn)
+            .await
+            .unwrap()
+            .iter()
+            .filter(|column| column.get::<i64, _>("pk") > 0)
+            .map(|column| column.get("name"))
+            .collect();
+        assert_eq!(key, ["id_comment", "kind"]);
+
+        let comment =
+            sqlx::query("SELECT kind, text, is_toxic FROM Comments WHERE id_comment = 11")
+                .fetch_one(&mut conn)
+                .await
+                .unwrap();
+        assert_eq!(comment.get::<String, _>("kind"), "issue_comment");
+        assert_eq!(comment.get::<String, _>("text"), "c1");
+        assert!(comment.get::<bool, _>("is_toxic"));
+
+        // A review comment may share its id with an issue comment.
+        conn.execute(
+            "INSERT INTO Comments (id_comment, kind, text) VALUES (11, 'review_comment', 'r1')",
+        )
+        .await
+        .unwrap();
+
+        let reactions: String =
+            sqlx::query_scalar("SELECT sql FROM sqlite_master WHERE name = 'CommentReactions'")
+                .fetch_one(&mut conn)
+                .await
+                .unwrap();
+        assert!(reactions.contains("REFERENCES Comments("));
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::{HashMap, HashSet};
+
+use chrono::{DateTime, Duration, Utc};
+use futures_util::StreamExt;
+use reqwest::Url;
+use serde::Deserialize;
+use sqlx::sqlite::SqliteConnection;
+
+use crate::github::Github;
+use crate::{store_issues, store_respository, Issue, IssueFilter, Repository};
+
+/// The search API never returns more than this many results for a query, no
+/// matter how many match.
+const SEARCH_RESULT_CAP: u64 = 1000;
+
+/// Date ranges are not split any further than this.
+const MIN_RANGE_MINUTES: i64 = 1;
+
+#[derive(Deserialize, Debug)]
+struct SearchPage {
+    total_count: u64,
+    items: Vec<SearchIssue>,
+}
+
+#[derive(Deserialize, Debug)]
+struct SearchIssue {
+    #[serde(flatten)]
+    issue: Issue,
+    repository_url: String,
+}
+
+/// How to search a date range, given how many results it has.
+#[derive(Debug, PartialEq)]
+enum Split {
+    /// Every result can be fetched.
+    Whole,
+    /// Too many results, search either side of this moment instead.
+    Halves(DateTime<Utc>),
+    /// Too many results, but the range is too short to split: only the first
+    /// ones can be fetched.
+    Truncated,
+}
+
+fn split(total_count: u64, since: DateTime<Utc>, until: DateTime<Utc>) -> Split {
+    if total_count <= SEARCH_RESULT_CAP {
+        Split::Whole
+    } else if until - since > Duration::minutes(MIN_RANGE_MINUTES) {
+        Split::Halves(since + (until - since) / 2)
+    } else {
+        Split::Truncated
+    }
+}
+
+/// Finds locked issues and pull requests created between `since` and `until`
+/// through the search API, storing every one matching `filter` along with its
+/// repository. Ranges with more results than the search API returns are split
+/// in half until each fits.
+pub async fn discover_issues(
+    conn: &mut SqliteConnection,
+    client: &Github,
+    filter: &IssueFilter,
+    since: DateTime<Utc>,
+    until: DateTime<Utc>,
+) {
+    let mut ranges = vec![("issue", since, until), ("pr", since, until)];
+    let mut repositories: HashMap<String, Option<i64>> = HashMap::new();
+
+    while let Some((kind, since, until)) = ranges.pop() {
+        let url = search_url(client, filter, kind, since, until);
+        println!("Searching locked issues: {}", url);
+
+        let mut pages = client.paginate::<SearchPage>(url.as_str());
+        let Some(first) = pages.next().await else {
+            continue;
+        };
+
+        match split(first.total_count, since, until) {
+            Split::Whole => {}
+            Split::Halves(middle) => {
+                ranges.push((kind, middle, until));
+                ranges.push((kind, since, middle));
+                continue;
+            }
+            Split::Truncated => {
+                let pages = SEARCH_RESULT_CAP.div_ceil(100) as usize;
+                client.record_truncation(url.to_string(), pages);
+            }
+        }
+
+        let mut found = first.items;
+        while let Some(page) = pages.next().await {
+            found.extend(page.items);
+        }
+
+        let mut issues = HashSet::new();
+        for SearchIssue {
+            mut issue,
+            repository_url,
+        } in found
+        {
+            if !filter.matches(&issue) {
+                continue;
+            }
+
+            let repository_id = match repositories.get(&repository_url) {
+                Some(repository_id) => *repository_id,
+                None => {
+                    let repository: Option<Repository> =
+                        client.get_json(&repository_url).await.ok();
+                    let repository_id = repository.as_ref().map(|repository| repository.id);
+                    if let Some(repository) = repository {
+                        println!("Found locked issues in repository: {}", repository.name);
+                        store_respository(conn, repository).await;
+                    }
+                    repositories.insert(repository_url, repository_id);
+                    repository_id
+                }
+            };
+
+            if repository_id.is_some() {
+                issue.repository_id = repository_id;
+                issues.insert(issue);
+            }
+        }
+
+        store_issues(conn, issues).await;
+    }
+}
+
+/// `kind` is `issue` or `pr`, the search API wants one or the other.
+fn search_url(
+    client: &Github,
+    filter: &IssueFilter,
+    kind: &str,
+    since: DateTime<Utc>,
+    until: DateTime<Utc>,
+) -> Url {
+    let mut query = format!("is:{} is:locked", kind);
+    if let [state] = filter.states.as_slice() {
+        query.push_str(&format!(" is:{}", state));
+    }
+    query.push_str(&format!(
+        " created:{}..{}",
+        since.format("%FT%TZ"),
+        until.format("%FT%TZ")
+    ));
+
+    Url::parse_with_params(
+        &client.url("/search/issues"),
+        &[("q", query.as_str()), ("per_page", "100")],
+    )
+    .expect("invalid search URL")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(date: &str) -> DateTime<Utc> {
+        date.parse().unwrap()
+    }
+
+    #[test]
+    fn split_keeps_ranges_within_the_result_cap() {
+        let (since, until) = (date("2023-01-01T00:00:00Z"), date("2023-03-01T00:00:00Z"));
+
+        assert_eq!(split(SEARCH_RESULT_CAP, since, until), Split::Whole);
+    }
+
+    #[test]
+    fn split_halves_ranges_over_the_result_cap() {
+        let (since, until) = (date("2023-01-01T00:00:00Z"), date("2023-01-03T00:00:00Z"));
+
+        assert_eq!(
+            split(SEARCH_RESULT_CAP + 1, since, until),
+            Split::Halves(date("2023-01-02T00:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn split_truncates_ranges_of_a_minute_or_less() {
+        let since = date("2023-01-01T00:00:00Z");
+
+        assert_eq!(
+            split(5000, since, date("2023-01-01T00:01:00Z")),
+            Split::Truncated
+        );
+        assert_eq!(
+            split(5000, since, date("2023-01-01T00:02:00Z")),
+            Split::Halves(date("2023-01-01T00:01:00Z"))
+        );
+    }
+}
@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+
+use futures_util::stream::{self, StreamExt};
+
+use crate::github::Github;
+use crate::{Repository, RepositoryMetadata};
+
+/// A cell of the sample. Dimensions that aren't stratified on are `None`.
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+pub struct Stratum {
+    pub language: Option<String>,
+    /// Lower bound of the star bucket.
+    pub stars: Option<i64>,
+    pub created_year: Option<i32>,
+}
+
+/// Which repositories to sample and how many of each kind.
+pub struct Strata {
+    languages: Vec<String>,
+    star_buckets: Vec<i64>,
+    years: Vec<i32>,
+    quota: Option<usize>,
+    counts: HashMap<Stratum, usize>,
+}
+
+impl Strata {
+    /// `star_buckets` are the lower bounds of each bucket, e.g. `0,10,100`
+    /// for 0-9, 10-99 and 100 or more stars.
+    pub fn new(
+        languages: Vec<String>,
+        mut star_buckets: Vec<i64>,
+        years: Vec<i32>,
+        quota: Option<usize>,
+    ) -> Self {
+        star_buckets.sort_unstable();
+        star_buckets.dedup();
+        Strata {
+            languages,
+            star_buckets,
+            years,
+            quota,
+            counts: HashMap::new(),
+        }
+    }
+
+    /// Repositories already sampled into each stratum by earlier runs.
+    pub fn counts(mut self, counts: HashMap<Stratum, usize>) -> Self {
+        self.counts = counts;
+        self
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.languages.is_empty()
+            || !self.star_buckets.is_empty()
+            || !self.years.is_empty()
+            || self.quota.is_some()
+    }
+
+    /// Whether every stratum has reached its quota.
+    pub fn is_full(&self) -> bool {
+        let Some(quota) = self.quota else {
+            return false;
+        };
+        let strata =
+            self.languages.len().max(1) * self.star_buckets.len().max(1) * self.years.len().max(1);
+        self.counts
+            .iter()
+            .filter(|&(stratum, &count)| self.contains(stratum) && count >= quota)
+            .count()
+            >= strata
+    }
+
+    /// Whether `stratum` is one `classify` can return, as opposed to one
+    /// left over from an earlier run stratified differently.
+    fn contains(&self, stratum: &Stratum) -> bool {
+        fn dimension<T: PartialEq>(wanted: &[T], value: &Option<T>) -> bool {
+            match value {
+                Some(value) => wanted.contains(value),
+                None => wanted.is_empty(),
+            }
+        }
+
+        dimension(&self.languages, &stratum.language)
+            && dimension(&self.star_buckets, &stratum.stars)
+            && dimension(&self.years, &stratum.created_year)
+    }
+
+    /// The stratum a repository falls into, if it falls into any.
+    pub fn classify(&self, metadata: &RepositoryMetadata) -> Option<Stratum> {
+        let language = if self.languages.is_empty() {
+            None
+        } else {
+            let language = metadata.language.as_deref()?;
+            let language = self
+                .languages
+                .iter()
+                .find(|wanted| wanted.eq_ignore_ascii_case(language))?;
+            Some(language.clone())
+        };
+
+        let stars = if self.star_buckets.is_empty() {
+            None
+        } else {
+            let bucket = self
+                .star_buckets
+                .iter()
+                .rev()
+                .find(|&&bucket| metadata.stargazers_count >= bucket)?;
+            Some(*bucket)
+        };
+
+        let created_year = if self.years.is_empty() {
+            None
+        } else {
+            let year: i32 = metadata.created_at.get(..4)?.parse().ok()?;
+            Some(*self.years.iter().find(|&&wanted| wanted == year)?)
+        };
+
+        Some(Stratum {
+            language,
+            stars,
+            created_year,
+        })
+    }
+
+    /// Count a repository towards `stratum`, unless it is already full.
+    fn accept(&mut self, stratum: &Stratum) -> bool {
+        let count = self.counts.entry(stratum.clone()).or_default();
+        if self.quota.is_some_and(|quota| *count >= quota) {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+}
+
+/// The repositories from `repositories` that fill a stratum with room left,
+/// along with the stratum each one was counted towards.
+pub async fn sample(
+    client: &Github,
+    strata: &mut Strata,
+    repositories: Vec<Repository>,
+) -> Vec<(Repository, Stratum)> {
+    let mut candidates = stream::iter(repositories)
+        .map(|repository| async move {
+            let url = client.url(&format!("/repos/{}", repository.full_name));
+            let metadata = client.get_json::<RepositoryMetadata>(&url).await.ok();
+            (repository, metadata)
+        })
+        .buffered(client.max_parallelism());
+
+    let mut sampled = Vec::new();
+    while let Some((repository, metadata)) = candidates.next().await {
+        let Some(stratum) = metadata.and_then(|metadata| strata.classify(&metadata)) else {
+            continue;
+        };
+        if strata.accept(&stratum) {
+            sampled.push((repository, stratum));
+        }
+    }
+
+    sampled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stratum(language: &str) -> Stratum {
+        Stratum {
+            language: Some(language.to_string()),
+            stars: None,
+            created_year: None,
+        }
+    }
+
+    #[test]
+    fn is_full_ignores_strata_of_earlier_runs() {
+        let counts = HashMap::from([(stratum("Rust"), 5), (stratum("Go"), 5)]);
+
+        let strata =
+            Strata::new(vec!["Python".to_string()], vec![], vec![], Some(5)).counts(counts.clone());
+        assert!(!strata.is_full());
+
+        let strata = Strata::new(vec!["Rust".to_string()], vec![], vec![], Some(5)).counts(counts);
+        assert!(strata.is_full());
+    }
+
+    #[test]
+    fn is_full_needs_every_stratum() {
+        let counts = HashMap::from([(stratum("Rust"), 5), (stratum("Go"), 4)]);
+        let languages = vec!["Rust".to_string(), "Go".to_string()];
+
+        let strata = Strata::new(languages, vec![], vec![], Some(5)).counts(counts);
+        assert!(!strata.is_full());
+    }
+}
@@ -0,0 +1,86 @@
+use futures_util::stream::{self, StreamExt};
+use serde::Deserialize;
+
+use crate::github::Github;
+
+#[derive(Deserialize, Debug)]
+pub struct Actor {
+    pub login: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Label {
+    pub name: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SourceIssue {
+    pub html_url: String,
+}
+
+/// What a `cross-referenced` event points at.
+#[derive(Deserialize, Debug)]
+pub struct Source {
+    pub issue: Option<SourceIssue>,
+}
+
+/// An entry of `/repos/{owner}/{name}/issues/{number}/timeline`. Which fields
+/// are present depends on `event`.
+#[derive(Deserialize, Debug)]
+pub struct TimelineEvent {
+    pub id: Option<i64>,
+    pub event: String,
+    pub actor: Option<Actor>,
+    pub created_at: Option<String>,
+    pub lock_reason: Option<String>,
+    pub label: Option<Label>,
+    pub assignee: Option<Actor>,
+    pub source: Option<Source>,
+    pub commit_id: Option<String>,
+}
+
+impl TimelineEvent {
+    /// What the event is about, e.g. the label for `labeled` or the issue
+    /// referencing this one for `cross-referenced`.
+    pub fn detail(&self) -> Option<&str> {
+        if let Some(label) = &self.label {
+            return Some(&label.name);
+        }
+        if let Some(assignee) = &self.assignee {
+            return Some(&assignee.login);
+        }
+        if let Some(issue) = self
+            .source
+            .as_ref()
+            .and_then(|source| source.issue.as_ref())
+        {
+            return Some(&issue.html_url);
+        }
+        self.lock_reason.as_deref().or(self.commit_id.as_deref())
+    }
+}
+
+/// The timeline of each of the given `(id_issue, comments_url)` issues.
+pub async fn fetch_timelines(
+    client: &Github,
+    issues: &[(i64, String)],
+) -> Vec<(i64, Vec<TimelineEvent>)> {
+    stream::iter(issues)
+        .filter_map(|(id_issue, comments_url)| async move {
+            let issue_url = comments_url.strip_suffix("/comments")?;
+            Some((*id_issue, format!("{}/timeline?per_page=100", issue_url)))
+        })
+        .map(|(id_issue, url)| async move {
+            println!("Retrieving Timeline: {}", url);
+
+            let events = client
+                .paginate::<Vec<TimelineEvent>>(&url)
+                .flat_map(stream::iter)
+                .collect()
+                .await;
+            (id_issue, events)
+        })
+        .buffer_unordered(client.max_parallelism())
+        .collect()
+        .await
+}
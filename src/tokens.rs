@@ -0,0 +1,319 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use reqwest::{
+    header::{ACCEPT, AUTHORIZATION, USER_AGENT},
+    Client,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::github::{Budget, FetchError, USER_AGENT_NAME};
+
+/// Installation tokens are refreshed this many minutes before they expire.
+const REFRESH_MARGIN_MINUTES: i64 = 5;
+
+/// A GitHub App, used to mint installation access tokens.
+pub struct App {
+    id: String,
+    key: EncodingKey,
+}
+
+#[derive(Serialize)]
+struct Claims {
+    iat: i64,
+    exp: i64,
+    iss: String,
+}
+
+#[derive(Deserialize)]
+struct AccessTokenPayload {
+    token: String,
+    expires_at: String,
+}
+
+struct AccessToken {
+    token: String,
+    expires_at: DateTime<Utc>,
+}
+
+impl App {
+    pub fn new(id: &str, key_path: &str) -> Result<Self, String> {
+        let pem = std::fs::read(key_path)
+            .map_err(|err| format!("failed to read app private key {}: {}", key_path, err))?;
+        let key = EncodingKey::from_rsa_pem(&pem)
+            .map_err(|err| format!("invalid app private key {}: {}", key_path, err))?;
+
+        Ok(App {
+            id: id.to_string(),
+            key,
+        })
+    }
+
+    /// A short lived JWT identifying the app itself.
+    fn jwt(&self) -> String {
+        let now = Utc::now().timestamp();
+        let claims = Claims {
+            // Backdated to allow for clock drift, as GitHub recommends.
+            iat: now - 60,
+            exp: now + 9 * 60,
+            iss: self.id.clone(),
+        };
+
+        jsonwebtoken::encode(&Header::new(Algorithm::RS256), &claims, &self.key)
+            .expect("failed to sign app JWT")
+    }
+
+    async fn access_token(
+        &self,
+        client: &Client,
+        api_url: &str,
+        installation_id: u64,
+    ) -> Result<AccessToken, FetchError> {
+        let url = format!(
+            "{}/app/installations/{}/access_tokens",
+            api_url, installation_id
+        );
+        println!("Requesting access token: {}", url);
+
+        let response = client
+            .post(&url)
+            .header(ACCEPT, "application/vnd.github+json")
+            .header(USER_AGENT, USER_AGENT_NAME)
+            .header(AUTHORIZATION, format!("Bearer {}", self.jwt()))
+            .send()
+            .await
+            .map_err(FetchError::Request)?;
+
+        let status = response.status();
+        let body = response.text().await.map_err(FetchError::Request)?;
+        if !status.is_success() {
+            return Err(FetchError::Status(status, body));
+        }
+
+        let payload: AccessTokenPayload =
+            serde_json::from_str(&body).map_err(FetchError::Decode)?;
+        let expires_at = DateTime::parse_from_rfc3339(&payload.expires_at)
+            .map(|expires_at| expires_at.with_timezone(&Utc))
+            .unwrap_or_else(|_| Utc::now() + chrono::Duration::hours(1));
+
+        Ok(AccessToken {
+            token: payload.token,
+            expires_at,
+        })
+    }
+}
+
+enum Credential {
+    Anonymous,
+    Personal(String),
+    Installation {
+        app: Arc<App>,
+        installation_id: u64,
+        access_token: tokio::sync::Mutex<Option<AccessToken>>,
+    },
+}
+
+/// A credential requests can be sent with, along with the rate limit budgets
+/// GitHub reported for it.
+pub struct Token {
+    credential: Credential,
+    budgets: Mutex<HashMap<String, Budget>>,
+}
+
+impl Token {
+    fn new(credential: Credential) -> Self {
+        Token {
+            credential,
+            budgets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Authenticates as an installation of `app`, minting a new access token
+    /// whenever the current one is about to expire.
+    pub fn installation(app: Arc<App>, installation_id: u64) -> Self {
+        Token::new(Credential::Installation {
+            app,
+            installation_id,
+            access_token: tokio::sync::Mutex::new(None),
+        })
+    }
+
+    /// The value for the `Authorization` header, if the token isn't anonymous.
+    pub async fn authorization(
+        &self,
+        client: &Client,
+        api_url: &str,
+    ) -> Result<Option<String>, FetchError> {
+        match &self.credential {
+            Credential::Anonymous => Ok(None),
+            Credential::Personal(secret) => Ok(Some(format!("Bearer {}", secret))),
+            Credential::Installation {
+                app,
+                installation_id,
+                access_token,
+            } => {
+                let mut access_token = access_token.lock().await;
+
+                let token = match access_token.take() {
+                    Some(token)
+                        if token.expires_at - chrono::Duration::minutes(REFRESH_MARGIN_MINUTES)
+                            > Utc::now() =>
+                    {
+                        token
+                    }
+                    _ => app.access_token(client, api_url, *installation_id).await?,
+                };
+                let authorization = format!("Bearer {}", token.token);
+                *access_token = Some(token);
+
+                Ok(Some(authorization))
+            }
+        }
+    }
+
+    pub fn update_budget(&self, resource: &str, budget: Budget) {
+        self.budgets
+            .lock()
+            .unwrap()
+            .insert(resource.to_string(), budget);
+    }
+
+    fn budget(&self, resource: &str) -> Option<Budget> {
+        self.budgets
+            .lock()
+            .unwrap()
+            .get(resource)
+            .copied()
+            .filter(|budget| !budget.has_reset())
+    }
+}
+
+/// Every credential we can spread requests over. Requests go out with
+/// whichever token has the most budget left for the resource they are
+/// billed against.
+pub struct TokenPool {
+    tokens: Vec<Token>,
+}
+
+impl TokenPool {
+    pub fn new(tokens: Vec<Token>) -> Result<Self, String> {
+        if tokens.is_empty() {
+            return Err(
+                "no GitHub credentials configured: set GITHUB_TOKEN or GITHUB_TOKENS, \
+                 pass --tokens-file or a GitHub App, or run with --unauthenticated"
+                    .to_string(),
+            );
+        }
+
+        Ok(TokenPool { tokens })
+    }
+
+    /// Personal access tokens listed in `tokens_file` (one per line),
+    /// `GITHUB_TOKENS` (separated by commas or whitespace) and `GITHUB_TOKEN`.
+    pub fn personal_tokens(tokens_file: Option<&str>) -> Result<Vec<Token>, String> {
+        let mut secrets = Vec::new();
+
+        if let Some(path) = tokens_file {
+            let contents = std::fs::read_to_string(path)
+                .map_err(|err| format!("failed to read tokens file {}: {}", path, err))?;
+            secrets.extend(
+                contents
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(String::from),
+            );
+        }
+
+        if let Ok(tokens) = std::env::var("GITHUB_TOKENS") {
+            secrets.extend(
+                tokens
+                    .split(|c: char| c == ',' || c.is_whitespace())
+                    .filter(|token| !token.is_empty())
+                    .map(String::from),
+            );
+        }
+
+        if let Ok(token) = std::env::var("GITHUB_TOKEN") {
+            secrets.push(token);
+        }
+
+        secrets.sort();
+        secrets.dedup();
+
+        Ok(secrets
+            .into_iter()
+            .map(|secret| Token::new(Credential::Personal(secret)))
+            .collect())
+    }
+
+    /// A pool with a single anonymous token, subject to GitHub's much lower
+    /// unauthenticated rate limit.
+    pub fn unauthenticated() -> Self {
+        TokenPool {
+            tokens: vec![Token::new(Credential::Anonymous)],
+        }
+    }
+
+    /// Whether every request goes out without credentials.
+    pub fn is_anonymous(&self) -> bool {
+        self.tokens
+            .iter()
+            .all(|token| matches!(token.credential, Credential::Anonymous))
+    }
+
+    /// Combined budget of every token for `resource`.
+    pub fn budget(&self, resource: &str) -> Option<Budget> {
+        self.tokens
+            .iter()
+            .filter_map(|token| token.budget(resource))
+            .reduce(|total, budget| Budget {
+                limit: total.limit + budget.limit,
+                remaining: total.remaining + budget.remaining,
+                reset: total.reset.min(budget.reset),
+            })
+    }
+
+    /// A token with budget left for `resource`, waiting for the first one to
+    /// reset if all of them are exhausted.
+    pub async fn acquire(&self, resource: &str) -> &Token {
+        loop {
+            match self.available(resource) {
+                Ok(token) => return token,
+                Err(wait) => {
+                    println!(
+                        "Rate limit for {} exhausted on all {} tokens, waiting {}s",
+                        resource,
+                        self.tokens.len(),
+                        wait.as_secs()
+                    );
+                    tokio::time::sleep(wait).await;
+                }
+            }
+        }
+    }
+
+    fn available(&self, resource: &str) -> Result<&Token, Duration> {
+        let remaining = |token: &Token| {
+            token
+                .budget(resource)
+                .map_or(u32::MAX, |budget| budget.remaining)
+        };
+
+        self.tokens
+            .iter()
+            .filter(|token| remaining(token) > 0)
+            .max_by_key(|token| remaining(token))
+            .ok_or_else(|| {
+                self.tokens
+                    .iter()
+                    .filter_map(|token| token.budget(resource))
+                    .map(|budget| budget.time_until_reset())
+                    .min()
+                    .unwrap_or_default()
+            })
+    }
+}
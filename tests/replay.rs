@@ -0,0 +1,211 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use sqlx::{Connection, Executor, Row, SqliteConnection};
+
+/// Recorded with `--record` against a local mock of the API at this URL,
+/// which replaying never connects to.
+const API_URL: &str = "http://127.0.0.1:8765";
+
+const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/replay");
+const SCHEMA: &str = include_str!("../db/schema.sql");
+
+fn run(dir: &Path, args: &[&str]) {
+    let status = Command::new(env!("CARGO_BIN_EXE_toxicity-dataset"))
+        .current_dir(dir)
+        .env_remove("GITHUB_TOKEN")
+        .env_remove("GITHUB_TOKENS")
+        .args(["--database-url", "sqlite:dataset.db"])
+        .args(["--api-url", API_URL, "--replay", FIXTURES])
+        .args(args)
+        .status()
+        .expect("failed to run toxicity-dataset");
+    assert!(status.success(), "{:?} failed with {}", args, status);
+}
+
+fn read_csv(path: PathBuf) -> Vec<Vec<String>> {
+    csv::Reader::from_path(path)
+        .unwrap()
+        .records()
+        .map(|record| record.unwrap().iter().map(String::from).collect())
+        .collect()
+}
+
+#[tokio::test]
+async fn replays_crawl_comments_and_csv() {
+    let dir = std::env::temp_dir().join(format!("toxicity-dataset-replay-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let url = format!("sqlite:{}?mode=rwc", dir.join("dataset.db").display());
+    let mut conn = SqliteConnection::connect(&url).await.unwrap();
+    conn.execute(SCHEMA).await.unwrap();
+
+    run(
+        &dir,
+        &["--iterations", "1", "--seed", "42", "--max-repo-id", "500"],
+    );
+
+    let issues: Vec<(i64, String)> =
+        sqlx::query("SELECT id_issue, lock_reason FROM Issues ORDER BY id_issue")
+            .fetch_all(&mut conn)
+            .await
+            .unwrap()
+            .iter()
+            .map(|issue| (issue.get("id_issue"), issue.get("lock_reason")))
+            .collect();
+    assert_eq!(
+        issues,
+        [
+            (1, "too heated".to_string()),
+            (3, "too heated".to_string()),
+            (4, "too heated".to_string())
+        ]
+    );
+
+    run(&dir, &["--populate-comments"]);
+
+    let comments: Vec<(i64, String, String)> =
+        sqlx::query("SELECT id_comment, kind, user_login FROM Comments ORDER BY id_comment")
+            .fetch_all(&mut conn)
+            .await
+            .unwrap()
+            .iter()
+            .map(|comment| {
+                (
+                    comment.get("id_comment"),
+                    comment.get("kind"),
+                    comment.get("user_login"),
+                )
+            })
+            .collect();
+    assert_eq!(
+        comments,
+        [
+            (11, "issue_comment".to_string(), "bob".to_string()),
+            (31, "issue_comment".to_string(), "bob".to_string()),
+            (41, "issue_comment".to_string(), "bob".to_string()),
+            (45, "review_comment".to_string(), "carol".to_string()),
+        ]
+    );
+
+    let minimized: Vec<(i64, Option<String>)> =
+        sqlx::query("SELECT id_comment, minimized_reason FROM Comments WHERE is_minimized = 1")
+            .fetch_all(&mut conn)
+            .await
+            .unwrap()
+            .iter()
+            .map(|comment| (comment.get("id_comment"), comment.get("minimized_reason")))
+            .collect();
+    assert_eq!(minimized, [(11, Some("abuse".to_string()))]);
+
+    let reactions: Vec<(i64, i64, i64, i64)> = sqlx::query(
+        "SELECT id_comment, total_count, plus_one, minus_one FROM CommentReactions \
+         ORDER BY id_comment",
+    )
+    .fetch_all(&mut conn)
+    .await
+    .unwrap()
+    .iter()
+    .map(|reaction| {
+        (
+            reaction.get("id_comment"),
+            reaction.get("total_count"),
+            reaction.get("plus_one"),
+            reaction.get("minus_one"),
+        )
+    })
+    .collect();
+    assert_eq!(reactions, [(11, 2, 1, 1), (31, 2, 1, 1), (41, 2, 1, 1)]);
+
+    let locks: Vec<(i64, i64, String, String)> = sqlx::query(
+        "SELECT id_event, id_issue, event, lock_reason FROM LockEvents ORDER BY id_event",
+    )
+    .fetch_all(&mut conn)
+    .await
+    .unwrap()
+    .iter()
+    .map(|lock| {
+        (
+            lock.get("id_event"),
+            lock.get("id_issue"),
+            lock.get("event"),
+            lock.get("lock_reason"),
+        )
+    })
+    .collect();
+    assert_eq!(
+        locks,
+        [
+            (101, 1, "locked".to_string(), "too heated".to_string()),
+            (301, 3, "locked".to_string(), "too heated".to_string()),
+            (401, 4, "locked".to_string(), "too heated".to_string()),
+        ]
+    );
+
+    let timeline: Vec<(i64, i64, Option<i64>, String)> = sqlx::query(
+        "SELECT id_issue, position, id_event, event FROM TimelineEvents \
+         WHERE id_issue = 1 ORDER BY position",
+    )
+    .fetch_all(&mut conn)
+    .await
+    .unwrap()
+    .iter()
+    .map(|event| {
+        (
+            event.get("id_issue"),
+            event.get("position"),
+            event.get("id_event"),
+            event.get("event"),
+        )
+    })
+    .collect();
+    assert_eq!(
+        timeline,
+        [
+            (1, 0, Some(100), "labeled".to_string()),
+            (1, 1, Some(101), "locked".to_string()),
+            (1, 2, None, "cross-referenced".to_string()),
+        ]
+    );
+    let timeline_events: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM TimelineEvents")
+        .fetch_one(&mut conn)
+        .await
+        .unwrap();
+    assert_eq!(timeline_events, 9);
+
+    let failures: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM FailedRequests")
+        .fetch_one(&mut conn)
+        .await
+        .unwrap();
+    assert_eq!(failures, 0);
+
+    conn.execute("UPDATE Comments SET is_toxic = 1 WHERE id_comment = 11")
+        .await
+        .unwrap();
+
+    run(&dir, &["--generate-csv"]);
+
+    assert_eq!(
+        read_csv(dir.join("data.csv")),
+        [
+            [
+                "2023-02-20T00:00:00Z",
+                "2023-02-20T00:00:00Z",
+                "before",
+                "1"
+            ],
+            ["2023-03-05T00:00:00Z", "2023-03-05T00:00:00Z", "after", "1"],
+        ]
+    );
+    assert_eq!(
+        read_csv(dir.join("stars_and_forks.csv")),
+        [
+            ["star", "2023-02-25T00:00:00Z", "before", "1"],
+            ["fork", "2023-03-10T00:00:00Z", "after", "1"],
+        ]
+    );
+
+    conn.close().await.unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}